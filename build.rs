@@ -1,13 +1,17 @@
-use chrono::{Local, Timelike};
-use std::{fs::File, io::Write};
+use chrono::{Local, SecondsFormat};
+use std::{
+    fs::{self, File},
+    io::Write,
+};
 
 fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
     let start = Local::now();
-    let hour = start.hour() as u8;
-    let min = start.minute() as u8;
-    let sec = start.second() as u8;
+    fs::create_dir_all("assets").unwrap();
     let mut output = File::create("assets/time.bin").unwrap();
     println!("编译时间 {start}");
-    output.write_all(&[hour, min, sec]).unwrap();
+    // 时间格式1996-12-19T16:39:57-08:00
+    output
+        .write_all(start.to_rfc3339_opts(SecondsFormat::Secs, false).as_bytes())
+        .unwrap();
 }
@@ -0,0 +1,370 @@
+//! 命令的确认和重发。
+//! 命令前面可以带一个序号`!17 @red,left`，其它节点发来的命令在序号前面带上发送方的地址`!2.17 @red,left`，
+//! 执行完回复`ACK 17`，解析或者执行失败回复`NAK 17 错误码`。
+//! 重发的命令在序号后面带上第几次`!2.17/2`，回复也带上`ACK 17/2`，经过中继时才不会被当成前一次丢掉。
+//! 最近收到的地址和序号记在[`Seen`]里，重发过来的同一条命令不会再执行一次，只把上次的回复再发一遍。
+//! 发送的一方用[`Retry`]决定什么时候重发、什么时候放弃
+
+use alloc::collections::VecDeque;
+use core::fmt::Display;
+
+/// 最多记住多少条命令
+pub const SEEN_LEN: usize = 16;
+
+/// 命令的序号，`from`是发出命令的节点地址，从串口直接发来的命令没有，`attempt`从1开始
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Id {
+    pub from: Option<u16>,
+    pub seq: u16,
+    pub attempt: u8,
+}
+
+impl Id {
+    /// 解析`!`后面的`序号`或者`地址.序号`，后面可以再带`/第几次`
+    fn parse(text: &str) -> Option<Id> {
+        let (from, seq) = match text.split_once('.') {
+            Some((from, seq)) => (Some(from.parse().ok()?), seq),
+            None => (None, text),
+        };
+        let (seq, attempt) = parse_seq(seq)?;
+        Some(Id { from, seq, attempt })
+    }
+}
+
+impl Display for Id {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.from {
+            Some(from) => write!(f, "!{}.", from)?,
+            None => write!(f, "!")?,
+        }
+        write!(f, "{}{}", self.seq, Attempt(self.attempt))
+    }
+}
+
+/// 解析`序号`或者`序号/第几次`，没写第几次的算第一次
+fn parse_seq(text: &str) -> Option<(u16, u8)> {
+    match text.split_once('/') {
+        Some((seq, attempt)) => Some((
+            seq.parse().ok()?,
+            attempt.parse().ok().filter(|attempt| *attempt > 1)?,
+        )),
+        None => Some((text.parse().ok()?, 1)),
+    }
+}
+
+/// 序号后面的`/第几次`，第一次不写
+struct Attempt(u8);
+
+impl Display for Attempt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            0 | 1 => Ok(()),
+            attempt => write!(f, "/{}", attempt),
+        }
+    }
+}
+
+/// 把行首的`!序号`拆出来，没有序号时返回None和原来的行
+pub fn split_id(line: &str) -> (Option<Id>, &str) {
+    let Some(rest) = line.strip_prefix('!') else {
+        return (None, line);
+    };
+    let (id, command) = rest.split_once(' ').unwrap_or((rest, ""));
+    match Id::parse(id) {
+        Some(id) => (Some(id), command.trim_start()),
+        None => (None, line),
+    }
+}
+
+/// 对带序号命令的回复
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    Ack(u16),
+    /// 序号和错误码
+    Nak(u16, u8),
+}
+
+impl Reply {
+    pub fn id(&self) -> u16 {
+        match self {
+            Reply::Ack(id) | Reply::Nak(id, _) => *id,
+        }
+    }
+
+    /// 解析回复的文本，不是ACK或NAK时返回None，不管回复的是第几次
+    pub fn parse(text: &str) -> Option<Reply> {
+        let mut words = text.split_whitespace();
+        let reply = match words.next()? {
+            "ACK" => Reply::Ack(parse_seq(words.next()?)?.0),
+            "NAK" => Reply::Nak(parse_seq(words.next()?)?.0, words.next()?.parse().ok()?),
+            _ => return None,
+        };
+        words.next().is_none().then_some(reply)
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Answer(*self, 1).fmt(f)
+    }
+}
+
+/// 对第几次发来的命令的回复
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Answer(pub Reply, pub u8);
+
+impl Display for Answer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Reply::Ack(id) => write!(f, "ACK {}{}", id, Attempt(self.1)),
+            Reply::Nak(id, code) => write!(f, "NAK {}{} {}", id, Attempt(self.1), code),
+        }
+    }
+}
+
+/// 最近处理过的命令和当时的回复，按发送方的地址和序号区分，
+/// 过了`window`或者满了之后挤掉最早的
+#[derive(Debug)]
+pub struct Seen {
+    /// 发送方的地址、回复和收到的时间
+    replies: VecDeque<(Option<u16>, Reply, u64)>,
+    window: u64,
+}
+
+impl Seen {
+    pub const fn new(window: u64) -> Self {
+        Self {
+            replies: VecDeque::new(),
+            window,
+        }
+    }
+
+    /// 这条命令在`window`之内处理过的话返回当时的回复
+    pub fn get(&self, id: Id, now: u64) -> Option<Reply> {
+        self.replies
+            .iter()
+            .find(|(from, reply, at)| {
+                *from == id.from && reply.id() == id.seq && now < at + self.window
+            })
+            .map(|(_, reply, _)| *reply)
+    }
+
+    /// 记下对`from`发来的命令的回复
+    pub fn insert(&mut self, from: Option<u16>, reply: Reply, now: u64) {
+        let window = self.window;
+        self.replies.retain(|(seen_from, seen, at)| {
+            !(*seen_from == from && seen.id() == reply.id()) && now < at + window
+        });
+        if self.replies.len() >= SEEN_LEN {
+            self.replies.pop_front();
+        }
+        self.replies.push_back((from, reply, now));
+    }
+}
+
+/// 等回复时该做什么
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Wait,
+    Resend,
+    GiveUp,
+}
+
+/// 发送方的重发计时，每重发一次等待时间翻倍，最多翻到第一次的八倍
+#[derive(Debug)]
+pub struct Retry {
+    pub id: u16,
+    sends: u8,
+    max_sends: u8,
+    timeout: u64,
+    max_timeout: u64,
+    deadline: u64,
+}
+
+impl Retry {
+    /// 第一次在`now`发出，等`timeout`没有回复就重发，总共最多发`max_sends`次
+    pub fn new(id: u16, now: u64, timeout: u64, max_sends: u8) -> Self {
+        Self {
+            id,
+            sends: 1,
+            max_sends,
+            timeout,
+            max_timeout: timeout * 8,
+            deadline: now + timeout,
+        }
+    }
+
+    /// 已经发了几次
+    pub fn sends(&self) -> u8 {
+        self.sends
+    }
+
+    /// 返回Resend时调用者要马上再发一次
+    pub fn poll(&mut self, now: u64) -> Action {
+        if now < self.deadline {
+            return Action::Wait;
+        }
+        if self.sends >= self.max_sends {
+            return Action::GiveUp;
+        }
+        self.sends += 1;
+        self.timeout = (self.timeout * 2).min(self.max_timeout);
+        self.deadline = now + self.timeout;
+        Action::Resend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn split_id_prefix() {
+        let id = |from, seq| {
+            Some(Id {
+                from,
+                seq,
+                attempt: 1,
+            })
+        };
+        assert_eq!(split_id("!17 @red,left"), (id(None, 17), "@red,left"));
+        assert_eq!(split_id("!3   ping"), (id(None, 3), "ping"));
+        assert_eq!(split_id("!2.17 ping"), (id(Some(2), 17), "ping"));
+        let resent = Id {
+            from: Some(2),
+            seq: 17,
+            attempt: 3,
+        };
+        assert_eq!(split_id("!2.17/3 ping"), (Some(resent), "ping"));
+        assert_eq!(split_id("!17/1 ping"), (None, "!17/1 ping"));
+        assert_eq!(split_id("@red,left"), (None, "@red,left"));
+        assert_eq!(split_id("!x ping"), (None, "!x ping"));
+        assert_eq!(split_id("!70000 ping"), (None, "!70000 ping"));
+        assert_eq!(split_id("!2. ping"), (None, "!2. ping"));
+        assert_eq!(split_id("!x.1 ping"), (None, "!x.1 ping"));
+    }
+
+    #[test]
+    fn id_round_trip() {
+        for (from, attempt) in [(None, 1), (Some(2), 1), (Some(2), 4)] {
+            let id = Id {
+                from,
+                seq: 17,
+                attempt,
+            };
+            assert_eq!(split_id(&id.to_string()), (Some(id), ""));
+        }
+    }
+
+    #[test]
+    fn answer_carries_attempt() {
+        assert_eq!(Answer(Reply::Ack(17), 1).to_string(), "ACK 17");
+        assert_eq!(Answer(Reply::Ack(17), 2).to_string(), "ACK 17/2");
+        assert_eq!(Answer(Reply::Nak(17, 3), 2).to_string(), "NAK 17/2 3");
+        assert_eq!(Reply::parse("ACK 17/2"), Some(Reply::Ack(17)));
+        assert_eq!(Reply::parse("NAK 17/2 3"), Some(Reply::Nak(17, 3)));
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        for reply in [Reply::Ack(17), Reply::Nak(3, 2)] {
+            assert_eq!(Reply::parse(&reply.to_string()), Some(reply));
+        }
+        assert_eq!(Reply::parse("ACK"), None);
+        assert_eq!(Reply::parse("ACK 1 2"), None);
+        assert_eq!(Reply::parse("NAK 1"), None);
+        assert_eq!(Reply::parse("pong 3"), None);
+    }
+
+    const LOCAL: Id = Id {
+        from: None,
+        seq: 1,
+        attempt: 1,
+    };
+
+    #[test]
+    fn seen_remembers_last_reply() {
+        let mut seen = Seen::new(100);
+        assert_eq!(seen.get(LOCAL, 0), None);
+        seen.insert(None, Reply::Nak(1, 2), 0);
+        assert_eq!(seen.get(LOCAL, 0), Some(Reply::Nak(1, 2)));
+        seen.insert(None, Reply::Ack(1), 0);
+        assert_eq!(seen.get(LOCAL, 0), Some(Reply::Ack(1)));
+    }
+
+    #[test]
+    fn seen_tells_senders_apart() {
+        let from = |from| Id {
+            from: Some(from),
+            ..LOCAL
+        };
+        let mut seen = Seen::new(100);
+        seen.insert(Some(2), Reply::Ack(1), 0);
+        assert_eq!(seen.get(from(2), 0), Some(Reply::Ack(1)));
+        assert_eq!(seen.get(from(3), 0), None);
+        assert_eq!(seen.get(LOCAL, 0), None);
+        // 重发的是同一条命令
+        let resent = Id {
+            attempt: 2,
+            ..from(2)
+        };
+        assert_eq!(seen.get(resent, 0), Some(Reply::Ack(1)));
+    }
+
+    #[test]
+    fn seen_expires() {
+        let mut seen = Seen::new(100);
+        seen.insert(None, Reply::Ack(1), 0);
+        assert_eq!(seen.get(LOCAL, 99), Some(Reply::Ack(1)));
+        assert_eq!(seen.get(LOCAL, 100), None);
+        // 过期的在插入新的时清掉
+        seen.insert(None, Reply::Ack(2), 100);
+        assert_eq!(seen.replies.len(), 1);
+    }
+
+    #[test]
+    fn seen_forgets_oldest() {
+        let mut seen = Seen::new(100);
+        for seq in 0..=SEEN_LEN as u16 {
+            seen.insert(None, Reply::Ack(seq), 0);
+        }
+        let id = |seq| Id { seq, ..LOCAL };
+        assert_eq!(seen.get(id(0), 0), None);
+        assert_eq!(seen.get(id(1), 0), Some(Reply::Ack(1)));
+        assert_eq!(
+            seen.get(id(SEEN_LEN as u16), 0),
+            Some(Reply::Ack(SEEN_LEN as u16))
+        );
+    }
+
+    #[test]
+    fn retry_backs_off_then_gives_up() {
+        let mut retry = Retry::new(5, 0, 10, 3);
+        assert_eq!(retry.poll(9), Action::Wait);
+        assert_eq!(retry.poll(10), Action::Resend);
+        assert_eq!(retry.sends(), 2);
+        // 第二次等20
+        assert_eq!(retry.poll(29), Action::Wait);
+        assert_eq!(retry.poll(30), Action::Resend);
+        // 第三次等40，发满三次后放弃
+        assert_eq!(retry.poll(69), Action::Wait);
+        assert_eq!(retry.poll(70), Action::GiveUp);
+        assert_eq!(retry.sends(), 3);
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let mut retry = Retry::new(5, 0, 10, 10);
+        let mut now = 0;
+        let mut waits = alloc::vec::Vec::new();
+        while retry.sends() < 6 {
+            let sent_at = now;
+            while retry.poll(now) == Action::Wait {
+                now += 1;
+            }
+            waits.push(now - sent_at);
+        }
+        assert_eq!(waits, [10, 20, 40, 80, 80]);
+    }
+}
@@ -0,0 +1,48 @@
+//! 命令前缀里的目标和回复前面的节点地址怎么写，节点本身的设置见固件里的`node`
+
+/// 组号的范围，组用一个u32的位图记录
+pub const MAX_GROUP: u8 = 31;
+
+/// 命令前缀里的目标
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    All,
+    Node(u16),
+    Group(u8),
+}
+
+impl Target {
+    /// 解析`*`、`地址`或者`g组号`，不带后面的跳数和冒号
+    pub fn parse(text: &str) -> Option<Target> {
+        match text.strip_prefix('g') {
+            _ if text == "*" => Some(Target::All),
+            Some(group) => group
+                .parse()
+                .ok()
+                .filter(|group| *group <= MAX_GROUP)
+                .map(Target::Group),
+            None => text.parse().ok().map(Target::Node),
+        }
+    }
+}
+
+/// 回复前面的节点地址，只能是数字
+pub fn is_address(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(Target::parse("*"), Some(Target::All));
+        assert_eq!(Target::parse("3"), Some(Target::Node(3)));
+        assert_eq!(Target::parse("g2"), Some(Target::Group(2)));
+        assert_eq!(Target::parse("g32"), None);
+        assert_eq!(Target::parse("time 12"), None);
+        assert_eq!(Target::parse("msg x"), None);
+        assert_eq!(Target::parse(""), None);
+    }
+}
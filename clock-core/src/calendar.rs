@@ -0,0 +1,505 @@
+//! 日历和时间的换算，只依赖秒数计算
+
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+pub static WEEKDAY: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// 由1970-01-01 00:00:00起的秒数换算出来的各个字段，只用来显示，不再用来计时
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1~12
+    pub month: u8,
+    /// 1~31
+    pub day: u8,
+    /// 0是星期一，6是星期日
+    pub weekday: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+}
+
+/// 只有年月日，用来设置日期
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UpdateIndex {
+    Sec1,
+    Sec10,
+    Min1,
+    Min10,
+    Hour1,
+    Hour10,
+    /// 日期变了，星期也跟着变
+    Day,
+    Month,
+    Year,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 1970-01-01到这一天的天数
+pub fn days_from_civil(date: Date) -> i64 {
+    let (year, month, day) = (date.year as i64, date.month as i64, date.day as i64);
+    // 把一年的开始挪到三月，闰日正好落在一年的最后
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// [`days_from_civil`]的反运算
+pub fn civil_from_days(days: i64) -> Date {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    Date {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+    }
+}
+
+/// 解析UTC偏移，`Z`、`+08:00`、`-05:30`这样的格式，返回秒数
+pub fn parse_offset(value: &str) -> Option<i32> {
+    if value == "Z" {
+        return Some(0);
+    }
+    let sign = match value.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let (hour, min) = value[1..].split_once(':')?;
+    let (hour, min) = (hour.parse::<i32>().ok()?, min.parse::<i32>().ok()?);
+    if hour > 14 || min >= 60 {
+        return None;
+    }
+    Some(sign * (hour * 3600 + min * 60))
+}
+
+/// 夏令时规则，夏令时期间在UTC偏移的基础上再加一小时
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DstRule {
+    None,
+    /// 欧洲：三月最后一个星期日到十月最后一个星期日，都在UTC 01:00切换
+    Eu,
+    /// 美国：三月第二个星期日到十一月第一个星期日，都在当地时间02:00切换
+    Us,
+}
+
+/// 时区，由UTC偏移和夏令时规则组成，(命令格式：tz ±HH:MM [eu|us])
+#[derive(Debug, Clone, Copy)]
+pub struct Zone {
+    /// 标准时间相对UTC的偏移，单位秒
+    pub offset: i32,
+    pub dst: DstRule,
+}
+
+/// 某年某月的第n个星期日（1970-01-01起的天数），n为0表示最后一个星期日
+fn sunday(year: u16, month: u8, n: u8) -> i64 {
+    if n == 0 {
+        let last = days_from_civil(Date {
+            year,
+            month,
+            day: days_in_month(year, month),
+        });
+        last - ((last + 3).rem_euclid(7) + 1) % 7
+    } else {
+        let first = days_from_civil(Date {
+            year,
+            month,
+            day: 1,
+        });
+        first + (6 - (first + 3).rem_euclid(7)) + 7 * (n as i64 - 1)
+    }
+}
+
+impl Zone {
+    pub const UTC: Zone = Zone {
+        offset: 0,
+        dst: DstRule::None,
+    };
+
+    /// 在`utc`这一刻（1970-01-01起的秒数）实际生效的偏移，包括夏令时
+    pub fn offset_at(&self, utc: i64) -> i32 {
+        let year = civil_from_days((utc + self.offset as i64).div_euclid(86400)).year;
+        let offset = self.offset as i64;
+        let (start, end) = match self.dst {
+            DstRule::None => return self.offset,
+            DstRule::Eu => (
+                sunday(year, 3, 0) * 86400 + 3600,
+                sunday(year, 10, 0) * 86400 + 3600,
+            ),
+            // 结束时当地已经是夏令时，02:00夏令时等于01:00标准时间
+            DstRule::Us => (
+                sunday(year, 3, 2) * 86400 + 2 * 3600 - offset,
+                sunday(year, 11, 1) * 86400 + 3600 - offset,
+            ),
+        };
+        if (start..end).contains(&utc) {
+            self.offset + 3600
+        } else {
+            self.offset
+        }
+    }
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.unsigned_abs();
+        write!(f, "UTC{}{:02}:{:02}", sign, offset / 3600, offset / 60 % 60)?;
+        match self.dst {
+            DstRule::None => Ok(()),
+            DstRule::Eu => write!(f, " eu"),
+            DstRule::Us => write!(f, " us"),
+        }
+    }
+}
+
+/// 比较两个显示出来的时间，得到哪些数字需要重画
+pub fn diff(old: &DateTime, new: &DateTime) -> Vec<UpdateIndex> {
+    let mut ans = Vec::new();
+    if old.sec % 10 != new.sec % 10 {
+        ans.push(UpdateIndex::Sec1);
+    }
+    if old.sec / 10 != new.sec / 10 {
+        ans.push(UpdateIndex::Sec10);
+    }
+    if old.min % 10 != new.min % 10 {
+        ans.push(UpdateIndex::Min1);
+    }
+    if old.min / 10 != new.min / 10 {
+        ans.push(UpdateIndex::Min10);
+    }
+    if old.hour % 10 != new.hour % 10 {
+        ans.push(UpdateIndex::Hour1);
+    }
+    if old.hour / 10 != new.hour / 10 {
+        ans.push(UpdateIndex::Hour10);
+    }
+    if old.date() != new.date() {
+        ans.push(UpdateIndex::Day);
+    }
+    if old.month != new.month {
+        ans.push(UpdateIndex::Month);
+    }
+    if old.year != new.year {
+        ans.push(UpdateIndex::Year);
+    }
+    ans
+}
+
+/// 解析小数秒的部分（小数点后面的数字），按毫秒返回，超过三位的部分舍掉
+pub fn parse_millis(fraction: &str) -> Option<u32> {
+    if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = &fraction[..fraction.len().min(3)];
+    let value: u32 = digits.parse().ok()?;
+    Some(value * 10u32.pow(3 - digits.len() as u32))
+}
+
+/// 解析ISO 8601格式的时间，例如`1996-12-19T16:39:57.250-08:00`，小数秒精确到毫秒。
+/// 返回日期、从零点开始的毫秒数，以及UTC偏移（秒，没写时为`None`）
+pub fn parse_iso(value: &str) -> Option<(Date, u32, Option<i32>)> {
+    let value = value.trim();
+    if !value.is_char_boundary(19) {
+        return None;
+    }
+    let (datetime, rest) = value.split_at(19);
+    let bytes = datetime.as_bytes();
+    if bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let number = |from: usize, to: usize| datetime[from..to].parse::<u16>().ok();
+    let date = Date {
+        year: number(0, 4)?,
+        month: number(5, 7)? as u8,
+        day: number(8, 10)? as u8,
+    };
+    let (hour, min, sec) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&date.month)
+        || date.day == 0
+        || date.day > days_in_month(date.year, date.month)
+        || hour >= 24
+        || min >= 60
+        || sec >= 60
+    {
+        return None;
+    }
+
+    let (millis, rest) = match rest.strip_prefix('.') {
+        Some(fraction) => {
+            let end = fraction
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(fraction.len());
+            (parse_millis(&fraction[..end])?, &fraction[end..])
+        }
+        None => (0, rest),
+    };
+    let offset = match rest {
+        "" => None,
+        _ => Some(parse_offset(rest)?),
+    };
+    Some((
+        date,
+        (hour as u32 * 3600 + min as u32 * 60 + sec as u32) * 1000 + millis,
+        offset,
+    ))
+}
+
+impl DateTime {
+    pub fn from_epoch(secs: i64) -> DateTime {
+        let days = secs.div_euclid(86400);
+        let secs_of_day = secs.rem_euclid(86400);
+        let date = civil_from_days(days);
+        DateTime {
+            year: date.year,
+            month: date.month,
+            day: date.day,
+            // 1970-01-01是星期四
+            weekday: (days + 3).rem_euclid(7) as u8,
+            hour: (secs_of_day / 3600) as u8,
+            min: (secs_of_day / 60 % 60) as u8,
+            sec: (secs_of_day % 60) as u8,
+        }
+    }
+
+    /// 从零点开始的秒数
+    pub fn secs_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.min as u32 * 60 + self.sec as u32
+    }
+
+    pub fn date(&self) -> Date {
+        Date {
+            year: self.year,
+            month: self.month,
+            day: self.day,
+        }
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.min, self.sec
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch(year: u16, month: u8, day: u8, hour: i64, min: i64, sec: i64) -> i64 {
+        days_from_civil(Date { year, month, day }) * 86400 + hour * 3600 + min * 60 + sec
+    }
+
+    fn tick(secs: i64) -> Vec<UpdateIndex> {
+        diff(&DateTime::from_epoch(secs), &DateTime::from_epoch(secs + 1))
+    }
+
+    #[test]
+    fn second_tick_only_touches_seconds() {
+        assert_eq!(tick(epoch(2024, 5, 1, 12, 34, 56)), [UpdateIndex::Sec1]);
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 12, 34, 49)),
+            [UpdateIndex::Sec1, UpdateIndex::Sec10]
+        );
+    }
+
+    #[test]
+    fn minute_rollover() {
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 12, 34, 59)),
+            [UpdateIndex::Sec1, UpdateIndex::Sec10, UpdateIndex::Min1]
+        );
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 12, 39, 59)),
+            [
+                UpdateIndex::Sec1,
+                UpdateIndex::Sec10,
+                UpdateIndex::Min1,
+                UpdateIndex::Min10
+            ]
+        );
+    }
+
+    #[test]
+    fn hour_rollover() {
+        let next = DateTime::from_epoch(epoch(2024, 5, 1, 9, 59, 59) + 1);
+        assert_eq!((next.hour, next.min, next.sec), (10, 0, 0));
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 9, 59, 59)),
+            [
+                UpdateIndex::Sec1,
+                UpdateIndex::Sec10,
+                UpdateIndex::Min1,
+                UpdateIndex::Min10,
+                UpdateIndex::Hour1,
+                UpdateIndex::Hour10
+            ]
+        );
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 12, 59, 59)),
+            [
+                UpdateIndex::Sec1,
+                UpdateIndex::Sec10,
+                UpdateIndex::Min1,
+                UpdateIndex::Min10,
+                UpdateIndex::Hour1
+            ]
+        );
+    }
+
+    #[test]
+    fn midnight_rollover_changes_date() {
+        let next = DateTime::from_epoch(epoch(2024, 5, 1, 23, 59, 59) + 1);
+        assert_eq!(
+            next.date(),
+            Date {
+                year: 2024,
+                month: 5,
+                day: 2
+            }
+        );
+        assert_eq!((next.hour, next.min, next.sec, next.weekday), (0, 0, 0, 3));
+        assert_eq!(
+            tick(epoch(2024, 5, 1, 23, 59, 59)),
+            [
+                UpdateIndex::Sec1,
+                UpdateIndex::Sec10,
+                UpdateIndex::Min1,
+                UpdateIndex::Min10,
+                UpdateIndex::Hour1,
+                UpdateIndex::Hour10,
+                UpdateIndex::Day
+            ]
+        );
+    }
+
+    #[test]
+    fn month_and_year_rollover() {
+        let leap = DateTime::from_epoch(epoch(2024, 2, 28, 23, 59, 59) + 1);
+        assert_eq!(
+            leap.date(),
+            Date {
+                year: 2024,
+                month: 2,
+                day: 29
+            }
+        );
+        let march = DateTime::from_epoch(epoch(2023, 2, 28, 23, 59, 59) + 1);
+        assert_eq!(
+            march.date(),
+            Date {
+                year: 2023,
+                month: 3,
+                day: 1
+            }
+        );
+        let changes = tick(epoch(2023, 12, 31, 23, 59, 59));
+        assert!(changes.contains(&UpdateIndex::Month));
+        assert!(changes.contains(&UpdateIndex::Year));
+    }
+
+    #[test]
+    fn epoch_round_trip() {
+        for secs in [0, 951_782_400, 1_709_251_199, 4_102_444_800] {
+            let datetime = DateTime::from_epoch(secs);
+            assert_eq!(
+                days_from_civil(datetime.date()) * 86400 + datetime.secs_of_day() as i64,
+                secs
+            );
+        }
+        assert_eq!(DateTime::from_epoch(0).weekday, 3);
+    }
+
+    #[test]
+    fn parse_iso_with_offset() {
+        let (date, secs, offset) = parse_iso("1996-12-19T16:39:57-08:00").unwrap();
+        assert_eq!(
+            date,
+            Date {
+                year: 1996,
+                month: 12,
+                day: 19
+            }
+        );
+        assert_eq!(secs, (16 * 3600 + 39 * 60 + 57) * 1000);
+        assert_eq!(offset, Some(-8 * 3600));
+        assert!(parse_iso("2023-02-29T00:00:00").is_none());
+    }
+
+    #[test]
+    fn parse_iso_keeps_milliseconds() {
+        let (_, ms, offset) = parse_iso("2024-05-01T12:00:03.25+08:00").unwrap();
+        assert_eq!(ms, 12 * 3_600_000 + 3_250);
+        assert_eq!(offset, Some(8 * 3600));
+        let (_, ms, offset) = parse_iso("2024-05-01T12:00:03.123456").unwrap();
+        assert_eq!(ms, 12 * 3_600_000 + 3_123);
+        assert_eq!(offset, None);
+        assert!(parse_iso("2024-05-01T12:00:03.").is_none());
+        assert_eq!(parse_millis("5"), Some(500));
+        assert_eq!(parse_millis("05"), Some(50));
+        assert_eq!(parse_millis("x"), None);
+    }
+
+    #[test]
+    fn eu_dst_switches_at_one_utc() {
+        let zone = Zone {
+            offset: 3600,
+            dst: DstRule::Eu,
+        };
+        assert_eq!(zone.offset_at(epoch(2024, 3, 31, 0, 59, 59)), 3600);
+        assert_eq!(zone.offset_at(epoch(2024, 3, 31, 1, 0, 0)), 7200);
+        assert_eq!(zone.offset_at(epoch(2024, 10, 27, 1, 0, 0)), 3600);
+    }
+
+    #[test]
+    fn us_dst_switches_at_two_local() {
+        let zone = Zone {
+            offset: -5 * 3600,
+            dst: DstRule::Us,
+        };
+        assert_eq!(zone.offset_at(epoch(2024, 3, 10, 6, 59, 59)), -5 * 3600);
+        assert_eq!(zone.offset_at(epoch(2024, 3, 10, 7, 0, 0)), -4 * 3600);
+        assert_eq!(zone.offset_at(epoch(2024, 11, 3, 5, 59, 59)), -4 * 3600);
+        assert_eq!(zone.offset_at(epoch(2024, 11, 3, 6, 0, 0)), -5 * 3600);
+    }
+}
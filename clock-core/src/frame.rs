@@ -0,0 +1,288 @@
+//! 带校验的二进制帧，和文本命令走同一个串口。
+//! 帧格式：同步字节`0xa5`、版本、负载长度、序号、负载、CRC-16（小端）。
+//! CRC从版本算到负载结束，负载就是一条文本命令，解出来之后和文本命令一样执行。
+//! 文本命令都是ASCII，不会出现`0xa5`，所以看到这个字节就知道后面是一帧
+
+use alloc::vec::Vec;
+
+pub const SYNC: u8 = 0xa5;
+pub const VERSION: u8 = 1;
+
+/// 同步字节、版本、长度、序号
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+
+/// 长度只有一个字节
+pub const MAX_PAYLOAD: usize = u8::MAX as usize;
+
+#[derive(Debug, PartialEq)]
+pub enum FrameErr {
+    /// 负载超过了MAX_PAYLOAD
+    TooLong,
+}
+
+/// 解出来的一帧
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+/// CRC-16/CCITT-FALSE，多项式0x1021，初值0xffff
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// 把负载打包成一帧
+pub fn encode(seq: u8, payload: &[u8]) -> Result<Vec<u8>, FrameErr> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(FrameErr::TooLong);
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+    bytes.extend_from_slice(&[SYNC, VERSION, payload.len() as u8, seq]);
+    bytes.extend_from_slice(payload);
+    let crc = crc16(&bytes[1..]);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    Ok(bytes)
+}
+
+/// 一个字节一个字节喂进来的解码器。
+/// 版本不对或者CRC不对时丢掉开头的同步字节，从后面的下一个同步字节重新找帧，
+/// 所以半截的帧后面紧跟着的好帧不会被连累。
+/// 半截的帧后面跟着的是文本命令时，靠超时丢掉半截的帧，文本命令才不会被吞掉
+#[derive(Debug)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    /// 收到上一个字节时的计数
+    last: u64,
+    timeout: u64,
+}
+
+impl Decoder {
+    /// `timeout`是一帧里两个字节之间最多隔多久，单位和`now`一样
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            buf: Vec::new(),
+            last: 0,
+            timeout,
+        }
+    }
+
+    /// 正在收一帧，这时串口来的字节都应该交给解码器。
+    /// 上一个字节之后隔了`timeout`还没收完的帧是丢了尾巴，直接丢掉
+    pub fn is_busy(&mut self, now: u64) -> bool {
+        if now.saturating_sub(self.last) > self.timeout {
+            self.buf.clear();
+        }
+        !self.buf.is_empty()
+    }
+
+    /// 在`now`喂进一个字节，凑齐一帧时返回这一帧
+    pub fn push(&mut self, byte: u8, now: u64) -> Option<Frame> {
+        self.last = now;
+        self.buf.push(byte);
+        self.next_frame()
+    }
+
+    /// 从缓冲区里找下一个完整的帧，重新同步后缓冲区里可能还剩完整的帧，
+    /// 所以`push`返回帧之后可以接着调用这个，直到返回None
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            // 同步字节前面的都是垃圾
+            match self.buf.iter().position(|byte| *byte == SYNC) {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    self.buf.clear();
+                    return None;
+                }
+            }
+            if self.buf.len() < HEADER_LEN {
+                return None;
+            }
+            if self.buf[1] != VERSION {
+                self.buf.remove(0);
+                continue;
+            }
+            let end = HEADER_LEN + self.buf[2] as usize;
+            if self.buf.len() < end + CRC_LEN {
+                return None;
+            }
+            let crc = u16::from_le_bytes([self.buf[end], self.buf[end + 1]]);
+            if crc != crc16(&self.buf[1..end]) {
+                self.buf.remove(0);
+                continue;
+            }
+            let frame = Frame {
+                seq: self.buf[3],
+                payload: self.buf[HEADER_LEN..end].to_vec(),
+            };
+            self.buf.drain(..end + CRC_LEN);
+            return Some(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: u64 = 10;
+
+    /// 把一串字节在同一时刻全部喂给解码器，收集解出来的帧
+    fn decode_all(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for byte in bytes {
+            if let Some(frame) = decoder.push(*byte, 0) {
+                frames.push(frame);
+                while let Some(frame) = decoder.next_frame() {
+                    frames.push(frame);
+                }
+            }
+        }
+        frames
+    }
+
+    fn frame(seq: u8, payload: &[u8]) -> Frame {
+        Frame {
+            seq,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(7, b"@red,left").unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 9 + CRC_LEN);
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(decode_all(&mut decoder, &bytes), [frame(7, b"@red,left")]);
+        assert!(!decoder.is_busy(0));
+    }
+
+    #[test]
+    fn empty_and_longest_payload() {
+        let long = [b'x'; MAX_PAYLOAD];
+        let mut bytes = encode(0, b"").unwrap();
+        bytes.extend(encode(1, &long).unwrap());
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(
+            decode_all(&mut decoder, &bytes),
+            [frame(0, b""), frame(1, &long)]
+        );
+        assert_eq!(encode(2, &[0; MAX_PAYLOAD + 1]), Err(FrameErr::TooLong));
+    }
+
+    #[test]
+    fn back_to_back_frames() {
+        let mut bytes = Vec::new();
+        for seq in 0..3 {
+            bytes.extend(encode(seq, b"ping").unwrap());
+        }
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(
+            decode_all(&mut decoder, &bytes),
+            [frame(0, b"ping"), frame(1, b"ping"), frame(2, b"ping")]
+        );
+    }
+
+    #[test]
+    fn garbage_before_frame() {
+        let mut bytes = b"noise\n\x00\xff".to_vec();
+        bytes.extend(encode(3, b"reload").unwrap());
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(decode_all(&mut decoder, &bytes), [frame(3, b"reload")]);
+    }
+
+    #[test]
+    fn truncated_frame_followed_by_good_one() {
+        let first = encode(1, b"@red,left").unwrap();
+        let mut bytes = first[..first.len() - 4].to_vec();
+        bytes.extend(encode(2, b"@blue,right").unwrap());
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(decode_all(&mut decoder, &bytes), [frame(2, b"@blue,right")]);
+    }
+
+    #[test]
+    fn truncated_frame_waits_for_more() {
+        let bytes = encode(1, b"ping").unwrap();
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert!(decode_all(&mut decoder, &bytes[..5]).is_empty());
+        assert!(decoder.is_busy(TIMEOUT));
+        assert_eq!(decode_all(&mut decoder, &bytes[5..]), [frame(1, b"ping")]);
+    }
+
+    #[test]
+    fn truncated_frame_followed_by_text_times_out() {
+        let bytes = encode(1, b"@red,left").unwrap();
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert!(decode_all(&mut decoder, &bytes[..bytes.len() - 4]).is_empty());
+        // 过一会儿来的文本命令不再交给解码器
+        let mut text = Vec::new();
+        for byte in b"ping\n" {
+            if decoder.is_busy(TIMEOUT + 1) {
+                assert_eq!(decoder.push(*byte, TIMEOUT + 1), None);
+            } else {
+                text.push(*byte);
+            }
+        }
+        assert_eq!(text, b"ping\n");
+        // 之后的帧照常能解
+        assert_eq!(
+            decode_all(&mut decoder, &encode(2, b"ping").unwrap()),
+            [frame(2, b"ping")]
+        );
+    }
+
+    #[test]
+    fn corrupted_payload_is_dropped() {
+        let mut bad = encode(1, b"@red,left").unwrap();
+        // 一个字节出错，文本协议下这会变成另一条合法的命令
+        bad[5] = b'g';
+        let mut bytes = bad;
+        bytes.extend(encode(2, b"@red,left").unwrap());
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(decode_all(&mut decoder, &bytes), [frame(2, b"@red,left")]);
+    }
+
+    #[test]
+    fn corrupted_length_resyncs() {
+        let mut bad = encode(1, b"ping").unwrap();
+        bad[2] = 40;
+        let mut bytes = bad;
+        bytes.extend(encode(2, b"pong").unwrap());
+        bytes.extend(encode(3, b"reload").unwrap());
+        // 凑够错误的长度之前后面的帧都在缓冲区里，校验失败后要一起解出来
+        bytes.extend([b'.'; 40]);
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(
+            decode_all(&mut decoder, &bytes),
+            [frame(2, b"pong"), frame(3, b"reload")]
+        );
+    }
+
+    #[test]
+    fn wrong_version_is_skipped() {
+        let mut bytes = encode(1, b"ping").unwrap();
+        bytes[1] = VERSION + 1;
+        bytes.extend(encode(2, b"ping").unwrap());
+        let mut decoder = Decoder::new(TIMEOUT);
+        assert_eq!(decode_all(&mut decoder, &bytes), [frame(2, b"ping")]);
+    }
+}
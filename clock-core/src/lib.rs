@@ -0,0 +1,16 @@
+//! 不碰硬件的部分：节点地址、日历、定时命令的调度、LoRa模块的参数、二进制帧、确认重发和中继。
+//! 单独放在这个库里，可以在电脑上跑测试（见`scripts/test.sh`）
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub mod ack;
+pub mod address;
+pub mod calendar;
+pub mod frame;
+pub mod lora;
+pub mod relay;
+pub mod schedule;
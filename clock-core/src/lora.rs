@@ -0,0 +1,492 @@
+//! UART接口的LoRa模块（E32/E22这类带M0、M1模式引脚和AUX忙信号的）驱动，
+//! 切到配置模式后读写模块参数，平时模块工作在透明/定点传输模式，UART1就是收发数据的通道
+
+use core::fmt::Display;
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{InputPin, OutputPin},
+};
+use embedded_hal_nb::{
+    nb,
+    serial::{Read, Write},
+};
+
+/// 等AUX和等模块回复的最长时间，单位毫秒
+const TIMEOUT_MS: u32 = 1000;
+
+/// 模块型号，两种模块的配置模式引脚和寄存器格式不一样
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// E32：配置模式M0=1、M1=1，参数是5个字节
+    E32,
+    /// E22：配置模式M0=0、M1=1，参数是从00H开始的7个寄存器
+    E22,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoraErr {
+    /// AUX一直是低电平，或者模块没有回复
+    Timeout,
+    /// 模块的回复和请求对不上
+    BadResponse,
+    /// 这个型号不支持的参数值
+    Unsupported,
+    /// 串口或者引脚出错
+    Io,
+}
+
+/// 模块参数，写入时没有列出的参数（串口速率、前向纠错、唤醒时间等）都恢复成出厂值
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub address: u16,
+    pub channel: u8,
+    /// 空中速率，单位bps
+    pub air_rate: u32,
+    /// 发射功率档位，0最大，3最小，每档对应的dBm和模块的功率等级有关
+    pub power: u8,
+    /// 定点传输，数据前三个字节是目标地址和信道；false是透明传输
+    pub fixed: bool,
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "addr {} chan {} rate {} power {} {}",
+            self.address,
+            self.channel,
+            self.air_rate,
+            self.power,
+            if self.fixed { "fixed" } else { "transparent" }
+        )
+    }
+}
+
+/// 两种模块的空中速率编码，下标就是寄存器里的值
+const E32_AIR_RATES: [u32; 8] = [300, 1200, 2400, 4800, 9600, 19200, 19200, 19200];
+const E22_AIR_RATES: [u32; 8] = [2400, 2400, 2400, 4800, 9600, 19200, 38400, 62500];
+
+/// 串口固定用9600、8N1，和UART1的设置一致：E32是SPED的第5~3位011，E22是REG0的第7~5位011
+const E32_UART_9600_8N1: u8 = 0x18;
+const E22_UART_9600_8N1: u8 = 0x60;
+
+impl Config {
+    /// 按型号编码成参数字节，E32是5个字节，E22是7个寄存器
+    pub fn encode(&self, model: Model) -> Result<([u8; 7], usize), LoraErr> {
+        if self.power > 3 {
+            return Err(LoraErr::Unsupported);
+        }
+        let [high, low] = self.address.to_be_bytes();
+        match model {
+            Model::E32 => {
+                let rate = E32_AIR_RATES[..6]
+                    .iter()
+                    .position(|rate| *rate == self.air_rate)
+                    .ok_or(LoraErr::Unsupported)? as u8;
+                if self.channel > 31 {
+                    return Err(LoraErr::Unsupported);
+                }
+                // 推挽输出、唤醒时间250ms、打开前向纠错
+                let option = (self.fixed as u8) << 7 | 1 << 6 | 1 << 2 | self.power;
+                Ok((
+                    [
+                        high,
+                        low,
+                        E32_UART_9600_8N1 | rate,
+                        self.channel,
+                        option,
+                        0,
+                        0,
+                    ],
+                    5,
+                ))
+            }
+            Model::E22 => {
+                let rate = E22_AIR_RATES
+                    .iter()
+                    .rposition(|rate| *rate == self.air_rate)
+                    .ok_or(LoraErr::Unsupported)? as u8;
+                if self.channel > 83 {
+                    return Err(LoraErr::Unsupported);
+                }
+                // 网络号0，分包240字节，WOR周期2000ms
+                let reg3 = (self.fixed as u8) << 6 | 0b011;
+                Ok((
+                    [
+                        high,
+                        low,
+                        0,
+                        E22_UART_9600_8N1 | rate,
+                        self.power,
+                        self.channel,
+                        reg3,
+                    ],
+                    7,
+                ))
+            }
+        }
+    }
+
+    /// 从参数字节解码，`bytes`不含命令头
+    pub fn decode(model: Model, bytes: &[u8]) -> Result<Config, LoraErr> {
+        match (model, bytes) {
+            (Model::E32, [high, low, sped, chan, option]) => Ok(Config {
+                address: u16::from_be_bytes([*high, *low]),
+                channel: chan & 0x1f,
+                air_rate: E32_AIR_RATES[(sped & 0b111) as usize],
+                power: option & 0b11,
+                fixed: option & 1 << 7 != 0,
+            }),
+            (Model::E22, [high, low, _net, reg0, reg1, reg2, reg3]) => Ok(Config {
+                address: u16::from_be_bytes([*high, *low]),
+                channel: *reg2,
+                air_rate: E22_AIR_RATES[(reg0 & 0b111) as usize],
+                power: reg1 & 0b11,
+                fixed: reg3 & 1 << 6 != 0,
+            }),
+            _ => Err(LoraErr::BadResponse),
+        }
+    }
+}
+
+/// 模块驱动，串口不归它管，配置时由调用的人借给它
+pub struct Lora<M0, M1, AUX, D> {
+    model: Model,
+    m0: M0,
+    m1: M1,
+    aux: AUX,
+    delay: D,
+}
+
+impl<M0, M1, AUX, D> Lora<M0, M1, AUX, D>
+where
+    M0: OutputPin,
+    M1: OutputPin,
+    AUX: InputPin,
+    D: DelayNs,
+{
+    /// 创建驱动并让模块进入正常的收发模式
+    pub fn new(model: Model, m0: M0, m1: M1, aux: AUX, delay: D) -> Result<Self, LoraErr> {
+        let mut lora = Lora {
+            model,
+            m0,
+            m1,
+            aux,
+            delay,
+        };
+        lora.切换模式(false)?;
+        Ok(lora)
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// 换了型号不同的模块时不用重新烧录，引脚接法是一样的
+    pub fn set_model(&mut self, model: Model) -> Result<(), LoraErr> {
+        self.model = model;
+        self.切换模式(false)
+    }
+
+    /// 等AUX变高，模块空闲时AUX是高电平
+    fn 等待空闲(&mut self) -> Result<(), LoraErr> {
+        for _ in 0..TIMEOUT_MS {
+            if self.aux.is_high().map_err(|_| LoraErr::Io)? {
+                return Ok(());
+            }
+            self.delay.delay_ms(1);
+        }
+        Err(LoraErr::Timeout)
+    }
+
+    /// 在配置模式和正常收发模式之间切换
+    fn 切换模式(&mut self, config: bool) -> Result<(), LoraErr> {
+        self.等待空闲()?;
+        let (m0, m1) = match (self.model, config) {
+            (_, false) => (false, false),
+            (Model::E32, true) => (true, true),
+            (Model::E22, true) => (false, true),
+        };
+        self.m0.set_state(m0.into()).map_err(|_| LoraErr::Io)?;
+        self.m1.set_state(m1.into()).map_err(|_| LoraErr::Io)?;
+        // 手册要求切换后等AUX变高再等2ms
+        self.delay.delay_ms(2);
+        self.等待空闲()?;
+        self.delay.delay_ms(2);
+        Ok(())
+    }
+
+    /// 切到配置模式发出请求，读回`reply.len()`个字节，不管成功与否都切回正常模式
+    fn 请求<S: Read + Write>(
+        &mut self,
+        serial: &mut S,
+        request: &[u8],
+        reply: &mut [u8],
+    ) -> Result<(), LoraErr> {
+        self.切换模式(true)?;
+        let result = self.收发(serial, request, reply);
+        self.切换模式(false)?;
+        result
+    }
+
+    fn 收发<S: Read + Write>(
+        &mut self,
+        serial: &mut S,
+        request: &[u8],
+        reply: &mut [u8],
+    ) -> Result<(), LoraErr> {
+        // 丢掉之前没读走的数据
+        while serial.read().is_ok() {}
+        for byte in request {
+            nb::block!(serial.write(*byte)).map_err(|_| LoraErr::Io)?;
+        }
+        nb::block!(serial.flush()).map_err(|_| LoraErr::Io)?;
+
+        let mut received = 0;
+        let mut waited = 0;
+        while received < reply.len() {
+            match serial.read() {
+                Ok(byte) => {
+                    reply[received] = byte;
+                    received += 1;
+                }
+                Err(nb::Error::WouldBlock) if waited < TIMEOUT_MS => {
+                    self.delay.delay_ms(1);
+                    waited += 1;
+                }
+                Err(nb::Error::WouldBlock) => return Err(LoraErr::Timeout),
+                Err(nb::Error::Other(_)) => return Err(LoraErr::Io),
+            }
+        }
+        Ok(())
+    }
+
+    /// 读出模块当前的参数
+    pub fn read_config<S: Read + Write>(&mut self, serial: &mut S) -> Result<Config, LoraErr> {
+        match self.model {
+            Model::E32 => {
+                let mut reply = [0; 6];
+                self.请求(serial, &[0xc1, 0xc1, 0xc1], &mut reply)?;
+                if reply[0] != 0xc0 {
+                    return Err(LoraErr::BadResponse);
+                }
+                Config::decode(self.model, &reply[1..])
+            }
+            Model::E22 => {
+                let mut reply = [0; 10];
+                self.请求(serial, &[0xc1, 0x00, 0x07], &mut reply)?;
+                if reply[..3] != [0xc1, 0x00, 0x07] {
+                    return Err(LoraErr::BadResponse);
+                }
+                Config::decode(self.model, &reply[3..])
+            }
+        }
+    }
+
+    /// 写入参数，`save`为true时掉电保存，否则只在这次上电期间有效，返回模块回读的参数
+    pub fn write_config<S: Read + Write>(
+        &mut self,
+        serial: &mut S,
+        config: &Config,
+        save: bool,
+    ) -> Result<Config, LoraErr> {
+        let (params, len) = config.encode(self.model)?;
+        let head = if save { 0xc0 } else { 0xc2 };
+        match self.model {
+            Model::E32 => {
+                let mut request = [head; 6];
+                request[1..].copy_from_slice(&params[..len]);
+                let mut reply = [0; 6];
+                self.请求(serial, &request, &mut reply)?;
+                // E32写完后回读的帧头总是C0
+                if reply[0] != 0xc0 {
+                    return Err(LoraErr::BadResponse);
+                }
+                Config::decode(self.model, &reply[1..])
+            }
+            Model::E22 => {
+                let mut request = [head, 0x00, 0x07, 0, 0, 0, 0, 0, 0, 0];
+                request[3..].copy_from_slice(&params[..len]);
+                let mut reply = [0; 10];
+                self.请求(serial, &request, &mut reply)?;
+                if reply[..3] != [0xc1, 0x00, 0x07] {
+                    return Err(LoraErr::BadResponse);
+                }
+                Config::decode(self.model, &reply[3..])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::VecDeque, vec::Vec};
+    use core::convert::Infallible;
+
+    /// 模拟的串口：记下写出的字节，请求发完（flush）之后才能读到事先准备好的回复
+    #[derive(Default)]
+    struct MockSerial {
+        written: Vec<u8>,
+        replies: Vec<u8>,
+        incoming: VecDeque<u8>,
+    }
+
+    impl embedded_hal_nb::serial::ErrorType for MockSerial {
+        type Error = Infallible;
+    }
+
+    impl Read for MockSerial {
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.incoming.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write for MockSerial {
+        fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+            self.written.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            self.incoming.extend(self.replies.drain(..));
+            Ok(())
+        }
+    }
+
+    /// 记录电平的引脚，AUX一直是高电平
+    #[derive(Default)]
+    struct MockPin(bool);
+
+    impl embedded_hal::digital::ErrorType for MockPin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockPin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0 = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockPin {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            Ok(self.0)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.0)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn lora(model: Model) -> Lora<MockPin, MockPin, MockPin, NoDelay> {
+        Lora::new(
+            model,
+            MockPin::default(),
+            MockPin::default(),
+            MockPin(true),
+            NoDelay,
+        )
+        .unwrap()
+    }
+
+    fn config() -> Config {
+        Config {
+            address: 0x1234,
+            channel: 23,
+            air_rate: 2400,
+            power: 1,
+            fixed: true,
+        }
+    }
+
+    #[test]
+    fn e32_factory_defaults_decode() {
+        // 出厂参数：地址0、9600 8N1、2.4k、410+23MHz、透明传输、最大功率
+        let config = Config::decode(Model::E32, &[0x00, 0x00, 0x1a, 0x17, 0x44]).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                address: 0,
+                channel: 23,
+                air_rate: 2400,
+                power: 0,
+                fixed: false,
+            }
+        );
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for model in [Model::E32, Model::E22] {
+            let (bytes, len) = config().encode(model).unwrap();
+            assert_eq!(Config::decode(model, &bytes[..len]).unwrap(), config());
+        }
+    }
+
+    #[test]
+    fn unsupported_values_are_rejected() {
+        let rate = Config {
+            air_rate: 62500,
+            ..config()
+        };
+        assert_eq!(rate.encode(Model::E32), Err(LoraErr::Unsupported));
+        assert!(rate.encode(Model::E22).is_ok());
+
+        let channel = Config {
+            channel: 40,
+            ..config()
+        };
+        assert_eq!(channel.encode(Model::E32), Err(LoraErr::Unsupported));
+        assert!(channel.encode(Model::E22).is_ok());
+    }
+
+    #[test]
+    fn e32_read_sends_c1_and_returns_to_normal_mode() {
+        let mut lora = lora(Model::E32);
+        let mut serial = MockSerial::default();
+        serial.replies.extend([0xc0, 0x12, 0x34, 0x1a, 0x17, 0xc5]);
+        assert_eq!(
+            lora.read_config(&mut serial).unwrap(),
+            Config {
+                power: 1,
+                ..config()
+            }
+        );
+        assert_eq!(serial.written, [0xc1, 0xc1, 0xc1]);
+        assert!(!lora.m0.0 && !lora.m1.0);
+    }
+
+    #[test]
+    fn e22_write_sends_registers_and_checks_echo() {
+        let mut lora = lora(Model::E22);
+        let mut serial = MockSerial::default();
+        let (params, len) = config().encode(Model::E22).unwrap();
+        serial.replies.extend([0xc1, 0x00, 0x07]);
+        serial.replies.extend(&params[..len]);
+        assert_eq!(
+            lora.write_config(&mut serial, &config(), false).unwrap(),
+            config()
+        );
+        assert_eq!(serial.written[..3], [0xc2, 0x00, 0x07]);
+        assert_eq!(serial.written[3..], params[..len]);
+    }
+
+    #[test]
+    fn missing_reply_times_out() {
+        let mut lora = lora(Model::E32);
+        let mut serial = MockSerial::default();
+        assert_eq!(lora.read_config(&mut serial), Err(LoraErr::Timeout));
+    }
+}
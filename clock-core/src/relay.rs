@@ -0,0 +1,193 @@
+//! 中继：控制器够不着的节点靠中间的节点转发。
+//! 需要转发的消息在目标后面带上跳数，命令写成`3~2:@red,left`，回复写成`3~2>ACK 5`，
+//! 打开了中继的节点收到后把跳数减一再广播出去，跳数为0的只收不转。
+//! 同一条消息会从几条路径到达，最近见过的消息记在[`Recent`]里，第二次收到时直接丢掉，
+//! 既不会重复执行，也不会在中继之间来回转。自己发出和转发的消息也要记下，邻居转回来时不再转一次。
+//! 只按内容认，所以只记一条消息绕路过来的那一小会儿，之后再发同样的内容照常处理
+
+use crate::address::{is_address, Target};
+use alloc::{collections::VecDeque, format, string::String};
+
+/// 跳数最多是几，防止写错的跳数让消息一直转下去
+pub const MAX_TTL: u8 = 7;
+
+/// 经过中继来的命令，回复时带的跳数
+pub const REPLY_TTL: u8 = 3;
+
+/// 最多记住多少条消息
+pub const RECENT_LEN: usize = 32;
+
+/// 带跳数的一条消息，`head`是目标或者回复方的地址，`sep`是`:`或者`>`
+#[derive(Debug, PartialEq)]
+pub struct Hop<'a> {
+    pub head: &'a str,
+    pub ttl: u8,
+    pub sep: char,
+    pub body: &'a str,
+}
+
+impl<'a> Hop<'a> {
+    /// 行首的`目标~跳数:`或者`地址~跳数>`，没有跳数时返回None。
+    /// 目标和地址的写法和不带跳数时一样，`msg x~1:y`这样的命令不算
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let at = line.find([':', '>'])?;
+        let (head, ttl) = line[..at].split_once('~')?;
+        let ttl = ttl.parse().ok().filter(|ttl| *ttl <= MAX_TTL)?;
+        let sep = line[at..].chars().next()?;
+        let valid = match sep {
+            ':' => Target::parse(head).is_some(),
+            _ => is_address(head),
+        };
+        valid.then_some(Self {
+            head,
+            ttl,
+            sep,
+            body: &line[at + 1..],
+        })
+    }
+
+    /// 用来认出同一条消息，不算跳数和行尾的换行，转发过的和原来的算同一条。
+    /// 内容里有命令的序号和第几次，重发的算不同的消息
+    pub fn key(&self) -> u32 {
+        let mut hash = fnv1a(0x811c_9dc5, self.head.as_bytes());
+        hash = fnv1a(hash, &[self.sep as u8]);
+        fnv1a(hash, self.body.trim_end().as_bytes())
+    }
+
+    /// 转发出去的行，跳数已经用完时返回None
+    pub fn forward(&self) -> Option<String> {
+        let ttl = self.ttl.checked_sub(1)?;
+        Some(format!("{}~{}{}{}", self.head, ttl, self.sep, self.body))
+    }
+}
+
+/// FNV-1a，从`hash`接着算
+fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// 最近见过的消息和见到时的计数
+#[derive(Debug)]
+pub struct Recent {
+    entries: VecDeque<(u32, u64)>,
+    memory: u64,
+}
+
+impl Recent {
+    /// `memory`是一条消息记多久，单位和`now`一样
+    pub const fn new(memory: u64) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            memory,
+        }
+    }
+
+    /// 第一次见到（或者上次见到已经过了很久）时返回true并记下来
+    pub fn first_seen(&mut self, key: u32, now: u64) -> bool {
+        let memory = self.memory;
+        self.entries
+            .retain(|(_, seen)| now.saturating_sub(*seen) < memory);
+        if self.entries.iter().any(|(seen, _)| *seen == key) {
+            return false;
+        }
+        if self.entries.len() >= RECENT_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_and_reply() {
+        assert_eq!(
+            Hop::parse("3~2:@red,left"),
+            Some(Hop {
+                head: "3",
+                ttl: 2,
+                sep: ':',
+                body: "@red,left"
+            })
+        );
+        assert_eq!(
+            Hop::parse("12~0>ACK 5"),
+            Some(Hop {
+                head: "12",
+                ttl: 0,
+                sep: '>',
+                body: "ACK 5"
+            })
+        );
+        assert_eq!(Hop::parse("g1~1:time 12:30").unwrap().body, "time 12:30");
+    }
+
+    #[test]
+    fn parse_without_ttl() {
+        assert_eq!(Hop::parse("3:@red,left"), None);
+        assert_eq!(Hop::parse("3>pong 3"), None);
+        assert_eq!(Hop::parse("msg a~b:c"), None);
+        assert_eq!(Hop::parse("msg x~1:y"), None);
+        assert_eq!(Hop::parse("x~1>y"), None);
+        assert_eq!(Hop::parse("g32~1:ping"), None);
+        assert_eq!(Hop::parse("*~1>ping"), None);
+        assert_eq!(Hop::parse("3~9:ping"), None);
+        assert_eq!(Hop::parse("ping"), None);
+    }
+
+    #[test]
+    fn forward_decrements_ttl() {
+        let hop = Hop::parse("*~2:reload").unwrap();
+        let forwarded = hop.forward().unwrap();
+        assert_eq!(forwarded, "*~1:reload");
+        let hop = Hop::parse(&forwarded).unwrap();
+        assert_eq!(hop.forward().as_deref(), Some("*~0:reload"));
+        assert_eq!(Hop::parse("*~0:reload").unwrap().forward(), None);
+    }
+
+    #[test]
+    fn key_ignores_ttl() {
+        let key = |line| Hop::parse(line).unwrap().key();
+        assert_eq!(key("3~2:ping"), key("3~0:ping"));
+        // 自己发出去的行带着换行，收到的行没有
+        assert_eq!(key("3~2:ping\n"), key("3~2:ping"));
+        assert_ne!(key("3~2:ping"), key("4~2:ping"));
+        assert_ne!(key("3~2:ping"), key("3~2>ping"));
+    }
+
+    #[test]
+    fn key_tells_retries_apart() {
+        let key = |line| Hop::parse(line).unwrap().key();
+        assert_ne!(key("3~2:!2.17 ping"), key("3~2:!2.17/2 ping"));
+        assert_ne!(key("3~2:!2.17 ping"), key("3~2:!2.18 ping"));
+        assert_ne!(key("3~3>ACK 17"), key("3~3>ACK 17/2"));
+    }
+
+    #[test]
+    fn recent_drops_duplicates_for_a_while() {
+        let mut recent = Recent::new(10);
+        assert!(recent.first_seen(1, 0));
+        assert!(!recent.first_seen(1, 5));
+        assert!(recent.first_seen(2, 5));
+        // 过了记忆时间之后同样的内容算新消息
+        assert!(recent.first_seen(1, 10));
+        assert!(!recent.first_seen(2, 14));
+    }
+
+    #[test]
+    fn recent_forgets_oldest_when_full() {
+        let mut recent = Recent::new(100);
+        for key in 0..=RECENT_LEN as u32 {
+            assert!(recent.first_seen(key, 0));
+        }
+        assert!(recent.first_seen(0, 1));
+        assert!(!recent.first_seen(RECENT_LEN as u32, 1));
+    }
+}
@@ -0,0 +1,384 @@
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+/// 一天的秒数
+pub const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// 延时和周期的上限（一年），16MHz计数时远小于闹钟比较值的52位
+pub const MAX_DELAY_SECS: u32 = 366 * SECS_PER_DAY;
+
+/// 任务什么时候执行
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// 相对延时，到截止时间执行一次
+    Once,
+    /// 在一天中的某个时刻执行一次，值是从零点开始的秒数，已经过了就等到第二天
+    At(u32),
+    /// 每隔若干秒执行一次
+    Every(u32),
+    /// 每天在某个时刻执行，值和`At`一样
+    Daily(u32),
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Trigger::Once => Ok(()),
+            Trigger::At(at) => write!(f, "at {:02}:{:02}:{:02} ", at / 3600, at / 60 % 60, at % 60),
+            Trigger::Every(period) => write!(f, "every {}s ", period),
+            Trigger::Daily(at) => write!(
+                f,
+                "every day {:02}:{:02}:{:02} ",
+                at / 3600,
+                at / 60 % 60,
+                at % 60
+            ),
+        }
+    }
+}
+
+/// 一个等待执行的任务，deadline是绝对时间（SystemTimer的计数值），不是相对延时
+#[derive(Debug)]
+pub struct Entry<T> {
+    /// 加入时分配的编号，用来取消任务
+    pub id: u32,
+    pub deadline: u64,
+    pub trigger: Trigger,
+    /// 周期任务还要执行的次数，`None`表示一直重复
+    pub remaining: Option<u32>,
+    pub item: T,
+}
+
+/// 延时任务的调度器，所有任务按截止时间从早到晚排好，闹钟只需要对准第一个
+#[derive(Debug)]
+pub struct Scheduler<T> {
+    entries: Vec<Entry<T>>,
+    next_id: u32,
+    ticks_per_second: u64,
+}
+
+/// 从`now`（一天中的秒数）到下一次`at`还要等多少秒，正好是现在则为0
+fn secs_until(now: u32, at: u32) -> u32 {
+    (at + SECS_PER_DAY - now % SECS_PER_DAY) % SECS_PER_DAY
+}
+
+impl<T> Scheduler<T> {
+    pub fn new(ticks_per_second: u64) -> Self {
+        Scheduler {
+            entries: Vec::new(),
+            next_id: 1,
+            ticks_per_second,
+        }
+    }
+
+    /// 截止时间相同的任务按加入的先后执行
+    fn insert(&mut self, entry: Entry<T>) {
+        let index = self
+            .entries
+            .partition_point(|other| other.deadline <= entry.deadline);
+        self.entries.insert(index, entry);
+    }
+
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        id
+    }
+
+    /// 按截止时间插入，返回任务编号
+    pub fn push(&mut self, deadline: u64, item: T) -> u32 {
+        let id = self.allocate_id();
+        self.insert(Entry {
+            id,
+            deadline,
+            trigger: Trigger::Once,
+            remaining: None,
+            item,
+        });
+        id
+    }
+
+    /// 按触发方式算出第一次执行的时间再插入，`now`是当前的计数值，`time_of_day`是当前墙上时间（一天中的秒数）
+    ///
+    /// `repeat`是周期任务总共执行的次数，`None`表示一直重复，对只执行一次的任务没有意义
+    pub fn push_trigger(
+        &mut self,
+        now: u64,
+        time_of_day: u32,
+        trigger: Trigger,
+        repeat: Option<u32>,
+        item: T,
+    ) -> u32 {
+        let id = self.allocate_id();
+        let secs = match trigger {
+            Trigger::Once => 0,
+            Trigger::At(at) | Trigger::Daily(at) => secs_until(time_of_day, at),
+            Trigger::Every(period) => period,
+        };
+        self.insert(Entry {
+            id,
+            deadline: now + secs as u64 * self.ticks_per_second,
+            trigger,
+            remaining: repeat,
+            item,
+        });
+        id
+    }
+
+    /// 墙上时间被调整过之后，按新的时间重新计算所有按时刻执行的任务。
+    /// 正好在执行时刻上的每日任务，如果截止时间已经在半天以后，说明这一次刚执行过，推到明天
+    pub fn rebase(&mut self, now: u64, time_of_day: u32) {
+        let ticks_per_second = self.ticks_per_second;
+        let half_day = SECS_PER_DAY as u64 / 2 * ticks_per_second;
+        for entry in self.entries.iter_mut() {
+            if let Trigger::At(at) | Trigger::Daily(at) = entry.trigger {
+                let secs = match secs_until(time_of_day, at) {
+                    0 if entry.deadline > now + half_day => SECS_PER_DAY,
+                    secs => secs,
+                };
+                entry.deadline = now + secs as u64 * ticks_per_second;
+            }
+        }
+        // 稳定排序，截止时间相同的任务仍然保持加入的先后
+        self.entries.sort_by_key(|entry| entry.deadline);
+    }
+
+    /// 取消一个任务，编号不存在时返回`None`
+    pub fn cancel(&mut self, id: u32) -> Option<T> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(index).item)
+    }
+
+    /// 清空所有任务，返回被清掉的数量
+    pub fn clear(&mut self) -> usize {
+        let count = self.entries.len();
+        self.entries.clear();
+        count
+    }
+
+    /// 按执行顺序遍历所有任务
+    pub fn iter(&self) -> impl Iterator<Item = &Entry<T>> {
+        self.entries.iter()
+    }
+
+    /// 最早的截止时间，闹钟应该设在这里
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries.first().map(|entry| entry.deadline)
+    }
+}
+
+impl<T: Clone> Scheduler<T> {
+    /// 取出一个已经到期的任务，没有到期的任务时返回`None`
+    ///
+    /// 周期任务会算好下一次的截止时间重新排进去，编号不变，所以仍然可以列出和取消
+    pub fn pop_due(&mut self, now: u64) -> Option<T> {
+        match self.next_deadline() {
+            Some(deadline) if deadline <= now => {
+                let mut entry = self.entries.remove(0);
+                let period = match entry.trigger {
+                    Trigger::Once | Trigger::At(_) => return Some(entry.item),
+                    Trigger::Every(period) => period,
+                    Trigger::Daily(_) => SECS_PER_DAY,
+                };
+                match entry.remaining {
+                    Some(0 | 1) => return Some(entry.item),
+                    Some(remaining) => entry.remaining = Some(remaining - 1),
+                    None => {}
+                }
+                // 从上一次的截止时间往后推，不会因为中断来晚了而越来越慢；落后太多就从现在算起
+                entry.deadline += period as u64 * self.ticks_per_second;
+                if entry.deadline <= now {
+                    entry.deadline = now + period as u64 * self.ticks_per_second;
+                }
+                let item = entry.item.clone();
+                self.insert(entry);
+                Some(item)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(scheduler: &mut Scheduler<&'static str>, now: u64) -> Vec<&'static str> {
+        let mut fired = Vec::new();
+        while let Some(item) = scheduler.pop_due(now) {
+            fired.push(item);
+        }
+        fired
+    }
+
+    #[test]
+    fn max_delay_fits_in_alarm_target() {
+        // SystemTimer 16MHz计数，闹钟比较值只有52位
+        let mut scheduler = Scheduler::new(16_000_000);
+        scheduler.push_trigger(0, 0, Trigger::Every(MAX_DELAY_SECS), None, ());
+        assert!(scheduler.next_deadline().unwrap() < 1 << 52);
+    }
+
+    #[test]
+    fn later_insert_with_shorter_delay_fires_first() {
+        // #red,left,10 之后再发 #blue,right,2，蓝色应该在第2秒触发而不是第12秒
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push(10, "red");
+        scheduler.push(2, "blue");
+        assert_eq!(scheduler.next_deadline(), Some(2));
+        assert_eq!(drain(&mut scheduler, 1), Vec::<&str>::new());
+        assert_eq!(drain(&mut scheduler, 2), ["blue"]);
+        assert_eq!(scheduler.next_deadline(), Some(10));
+        assert_eq!(drain(&mut scheduler, 10), ["red"]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn arbitrary_insert_order_is_sorted() {
+        let mut scheduler = Scheduler::new(1);
+        for (deadline, item) in [(30, "c"), (10, "a"), (50, "e"), (20, "b"), (40, "d")] {
+            scheduler.push(deadline, item);
+        }
+        assert_eq!(drain(&mut scheduler, 100), ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn equal_deadlines_keep_insertion_order() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push(5, "first");
+        scheduler.push(3, "early");
+        scheduler.push(5, "second");
+        scheduler.push(5, "third");
+        assert_eq!(
+            drain(&mut scheduler, 5),
+            ["early", "first", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn only_due_entries_are_popped() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push(100, "late");
+        scheduler.push(50, "soon");
+        assert_eq!(drain(&mut scheduler, 75), ["soon"]);
+        assert_eq!(scheduler.next_deadline(), Some(100));
+        scheduler.push(80, "middle");
+        assert_eq!(scheduler.next_deadline(), Some(80));
+        assert_eq!(drain(&mut scheduler, 1000), ["middle", "late"]);
+    }
+
+    #[test]
+    fn cancel_removes_only_that_entry() {
+        let mut scheduler = Scheduler::new(1);
+        let a = scheduler.push(10, "a");
+        let b = scheduler.push(5, "b");
+        let c = scheduler.push(20, "c");
+        assert_ne!(a, b);
+        assert_eq!(scheduler.cancel(b), Some("b"));
+        assert_eq!(scheduler.cancel(b), None);
+        assert_eq!(scheduler.next_deadline(), Some(10));
+        let ids: Vec<u32> = scheduler.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [a, c]);
+    }
+
+    #[test]
+    fn clear_empties_the_queue() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push(1, "a");
+        scheduler.push(2, "b");
+        assert_eq!(scheduler.clear(), 2);
+        assert_eq!(scheduler.next_deadline(), None);
+        assert_eq!(drain(&mut scheduler, 100), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn at_time_later_today() {
+        let mut scheduler = Scheduler::new(1);
+        // 现在是18:00:00，任务在18:30:00
+        scheduler.push_trigger(1000, 18 * 3600, Trigger::At(18 * 3600 + 1800), None, "red");
+        assert_eq!(scheduler.next_deadline(), Some(1000 + 1800));
+    }
+
+    #[test]
+    fn at_time_already_passed_rolls_to_tomorrow() {
+        let mut scheduler = Scheduler::new(1);
+        // 现在是19:00:00，18:30:00已经过了，要等到第二天
+        scheduler.push_trigger(0, 19 * 3600, Trigger::At(18 * 3600 + 1800), None, "red");
+        assert_eq!(
+            scheduler.next_deadline(),
+            Some((SECS_PER_DAY - 1800) as u64)
+        );
+    }
+
+    #[test]
+    fn rebase_follows_clock_adjustment_and_reorders() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push(100, "relative");
+        // 现在是12:00:00，任务在12:01:00
+        scheduler.push_trigger(0, 12 * 3600, Trigger::At(12 * 3600 + 60), None, "at");
+        assert_eq!(drain(&mut scheduler, 60), ["at"]);
+
+        scheduler.push_trigger(60, 12 * 3600 + 60, Trigger::At(12 * 3600 + 120), None, "at");
+        assert_eq!(scheduler.next_deadline(), Some(100));
+        // 时间被往后调了50秒，按时刻执行的任务提前到相对任务前面，相对任务不受影响
+        scheduler.rebase(70, 12 * 3600 + 120);
+        assert_eq!(drain(&mut scheduler, 70), ["at"]);
+        assert_eq!(scheduler.next_deadline(), Some(100));
+    }
+
+    #[test]
+    fn every_repeats_until_count_runs_out() {
+        let mut scheduler = Scheduler::new(1);
+        let id = scheduler.push_trigger(0, 0, Trigger::Every(15), Some(3), "green");
+        assert_eq!(drain(&mut scheduler, 14), Vec::<&str>::new());
+        assert_eq!(drain(&mut scheduler, 15), ["green"]);
+        assert_eq!(scheduler.iter().next().unwrap().id, id);
+        assert_eq!(scheduler.next_deadline(), Some(30));
+        // 晚到的中断不会让后面的周期往后错
+        assert_eq!(drain(&mut scheduler, 32), ["green"]);
+        assert_eq!(scheduler.next_deadline(), Some(45));
+        assert_eq!(drain(&mut scheduler, 45), ["green"]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn every_forever_can_be_cancelled() {
+        let mut scheduler = Scheduler::new(1);
+        let id = scheduler.push_trigger(0, 0, Trigger::Every(10), None, "tick");
+        for round in 1..=5 {
+            assert_eq!(drain(&mut scheduler, round * 10), ["tick"]);
+        }
+        assert_eq!(scheduler.cancel(id), Some("tick"));
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn daily_fires_again_next_day() {
+        let mut scheduler = Scheduler::new(1);
+        // 现在是07:00:00，每天08:00:00执行
+        scheduler.push_trigger(0, 7 * 3600, Trigger::Daily(8 * 3600), None, "reload");
+        assert_eq!(drain(&mut scheduler, 3600), ["reload"]);
+        assert_eq!(scheduler.next_deadline(), Some(3600 + SECS_PER_DAY as u64));
+    }
+
+    #[test]
+    fn rebase_right_after_daily_fires_keeps_it_for_tomorrow() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push_trigger(0, 7 * 3600, Trigger::Daily(8 * 3600), None, "reload");
+        assert_eq!(drain(&mut scheduler, 3600), ["reload"]);
+        // 08:00:00这一分钟开始时时钟中断会重新计算，不能让它再执行一次
+        scheduler.rebase(3600, 8 * 3600);
+        assert_eq!(drain(&mut scheduler, 3600), Vec::<&str>::new());
+        assert_eq!(scheduler.next_deadline(), Some(3600 + SECS_PER_DAY as u64));
+    }
+
+    #[test]
+    fn rebase_just_before_daily_fires_keeps_it_due() {
+        let mut scheduler = Scheduler::new(1);
+        scheduler.push_trigger(0, 7 * 3600, Trigger::Daily(8 * 3600), None, "reload");
+        // 时钟中断比调度的闹钟早一点到，这时还没执行过
+        scheduler.rebase(3599, 8 * 3600);
+        assert_eq!(drain(&mut scheduler, 3599), ["reload"]);
+        assert_eq!(scheduler.next_deadline(), Some(3599 + SECS_PER_DAY as u64));
+    }
+}
@@ -0,0 +1,10 @@
+#!/bin/bash
+# clock-core的测试在电脑上跑。
+# .cargo/config.toml里为固件打开了build-std，而且按目录向上查找，
+# 所以要在仓库外面的目录运行cargo，只用--manifest-path指到这个库
+
+set -e
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+cd "${TMPDIR:-/tmp}"
+cargo +nightly test --manifest-path "$root/clock-core/Cargo.toml" "$@"
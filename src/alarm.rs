@@ -0,0 +1,233 @@
+//! 每天定时响的闹钟，响的时候整屏或者一个灯闪烁，直到用ack命令或者按键确认。
+//! 闹钟存在flash里，掉电之后也不会丢；软复位时连同今天响没响过一起从RTC内存的备份恢复
+
+use crate::backup::{crc32, decode_lamp, encode_lamp};
+use crate::calendar::{days_from_civil, DateTime};
+use crate::command::Position;
+use crate::flash::{self, FlashErr};
+use crate::screen;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use critical_section::{CriticalSection, Mutex};
+use embedded_graphics::pixelcolor::{
+    raw::{RawData, RawU16},
+    Rgb565,
+};
+
+/// 最多能设几个闹钟，RTC内存里的备份按这个数留了位置
+pub const MAX_ALARMS: usize = 8;
+
+/// 所有闹钟，按添加的顺序排列，序号就是在这里的下标
+pub static ALARMS: Mutex<RefCell<Vec<Alarm>>> = Mutex::new(RefCell::new(Vec::new()));
+
+/// 正在响的闹钟
+pub static RINGING: Mutex<RefCell<Option<Ringing>>> = Mutex::new(RefCell::new(None));
+
+/// 时钟中断切换了亮灭，等主循环去画，整屏重画太慢，不能放在中断里
+static REDRAW: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// flash里闹钟配置开头的标记，改了格式就换一个
+const FLASH_MAGIC: u32 = 0x414c_4d31;
+
+/// flash里每个闹钟占的字节：秒数、颜色、灯的位置。
+/// 最后一次响的那天不存，读出来时按添加闹钟的规则重新算
+const STORED_LEN: usize = 4 + 2 + 1;
+
+/// 闹钟从这里开始，前面是标记和闹钟的个数
+const STORED_AT: usize = 4 + 1;
+
+const STORED_CRC_AT: usize = STORED_AT + STORED_LEN * MAX_ALARMS;
+
+/// flash按字读写
+const STORED_WORDS: usize = (STORED_CRC_AT + 4).div_ceil(4);
+
+#[derive(Debug, Clone)]
+pub struct Alarm {
+    /// 当地时间从零点开始的秒数
+    pub secs_of_day: u32,
+    pub color: Rgb565,
+    /// 闪烁的灯，None表示整屏闪烁
+    pub lamp: Option<Position>,
+    /// 最后一次响的那天（1970-01-01起的天数），同一天不会再响
+    pub fired_day: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ringing {
+    pub color: Rgb565,
+    pub lamp: Option<Position>,
+    /// 当前这一拍是亮还是灭
+    pub on: bool,
+}
+
+fn 今天(local: &DateTime) -> i32 {
+    days_from_civil(local.date()) as i32
+}
+
+/// 今天已经过了这个时刻的话算作今天响过了，从明天开始响
+fn 上次响的那天(local: &DateTime, secs_of_day: u32) -> i32 {
+    let today = 今天(local);
+    if local.secs_of_day() >= secs_of_day {
+        today
+    } else {
+        today - 1
+    }
+}
+
+/// 添加一个闹钟，今天已经过了这个时刻的话从明天开始响，返回序号，闹钟满了返回None
+pub fn 添加闹钟(
+    local: &DateTime,
+    secs_of_day: u32,
+    color: Rgb565,
+    lamp: Option<Position>,
+) -> Option<usize> {
+    critical_section::with(|cs| {
+        let mut alarms = ALARMS.borrow_ref_mut(cs);
+        if alarms.len() >= MAX_ALARMS {
+            return None;
+        }
+        alarms.push(Alarm {
+            secs_of_day,
+            color,
+            lamp,
+            fired_day: 上次响的那天(local, secs_of_day),
+        });
+        Some(alarms.len() - 1)
+    })
+}
+
+/// 按序号删除一个闹钟
+pub fn 删除闹钟(index: usize) -> Option<Alarm> {
+    critical_section::with(|cs| {
+        let mut alarms = ALARMS.borrow_ref_mut(cs);
+        (index < alarms.len()).then(|| alarms.remove(index))
+    })
+}
+
+/// 把所有闹钟写进flash，添加和删除闹钟之后调用
+pub fn 保存闹钟() -> Result<(), FlashErr> {
+    let mut bytes = [0u8; STORED_WORDS * 4];
+    critical_section::with(|cs| {
+        let alarms = ALARMS.borrow_ref(cs);
+        bytes[0..4].copy_from_slice(&FLASH_MAGIC.to_le_bytes());
+        bytes[4] = alarms.len().min(MAX_ALARMS) as u8;
+        for (alarm, chunk) in alarms.iter().zip(
+            bytes[STORED_AT..STORED_CRC_AT]
+                .as_chunks_mut::<STORED_LEN>()
+                .0,
+        ) {
+            chunk[0..4].copy_from_slice(&alarm.secs_of_day.to_le_bytes());
+            chunk[4..6].copy_from_slice(&RawU16::from(alarm.color).into_inner().to_le_bytes());
+            chunk[6] = encode_lamp(&alarm.lamp);
+        }
+    });
+    let crc = crc32(&bytes[..STORED_CRC_AT]);
+    bytes[STORED_CRC_AT..STORED_CRC_AT + 4].copy_from_slice(&crc.to_le_bytes());
+    let words: Vec<u32> = bytes
+        .as_chunks::<4>()
+        .0
+        .iter()
+        .map(|word| u32::from_le_bytes(*word))
+        .collect();
+    flash::写入扇区(flash::CONFIG_ADDR, &words)
+}
+
+/// 从flash读出闹钟，没有存过或者内容无效时返回空的
+pub fn 读取闹钟(local: &DateTime) -> Vec<Alarm> {
+    let Ok(words) = flash::读取::<STORED_WORDS>(flash::CONFIG_ADDR) else {
+        return Vec::new();
+    };
+    let mut bytes = [0u8; STORED_WORDS * 4];
+    for (word, chunk) in words.iter().zip(bytes.as_chunks_mut::<4>().0) {
+        *chunk = word.to_le_bytes();
+    }
+    let stored_crc =
+        u32::from_le_bytes(bytes[STORED_CRC_AT..STORED_CRC_AT + 4].try_into().unwrap());
+    if words[0] != FLASH_MAGIC
+        || stored_crc != crc32(&bytes[..STORED_CRC_AT])
+        || bytes[4] as usize > MAX_ALARMS
+    {
+        return Vec::new();
+    }
+    bytes[STORED_AT..STORED_CRC_AT]
+        .as_chunks::<STORED_LEN>()
+        .0
+        .iter()
+        .take(bytes[4] as usize)
+        .map(|chunk| {
+            let secs_of_day = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+            Alarm {
+                secs_of_day,
+                color: Rgb565::from(RawU16::new(u16::from_le_bytes([chunk[4], chunk[5]]))),
+                lamp: decode_lamp(chunk[6]),
+                fired_day: 上次响的那天(local, secs_of_day),
+            }
+        })
+        .collect()
+}
+
+/// 时钟中断每次都调用：到点的闹钟开始响，正在响的闹钟亮灭切换一次，真正的重画留给主循环。
+/// 按“今天还没响过并且已经过了这个时刻”判断，时钟被往回调也不会在同一天响第二次
+pub fn 检查闹钟(cs: CriticalSection, local: &DateTime) {
+    let today = 今天(local);
+    let now = local.secs_of_day();
+    for alarm in ALARMS.borrow_ref_mut(cs).iter_mut() {
+        if alarm.fired_day < today && now >= alarm.secs_of_day {
+            alarm.fired_day = today;
+            // 同时到点的闹钟只响最后一个，一次确认就都停了
+            RINGING.borrow_ref_mut(cs).replace(Ringing {
+                color: alarm.color,
+                lamp: alarm.lamp.clone(),
+                on: false,
+            });
+        }
+    }
+
+    if let Some(ringing) = RINGING.borrow_ref_mut(cs).as_mut() {
+        ringing.on = !ringing.on;
+        REDRAW.borrow(cs).set(true);
+    }
+}
+
+/// 主循环里调用，时钟中断切换过亮灭的话按现在的状态画一次
+pub fn 刷新闪烁() {
+    let ringing = critical_section::with(|cs| {
+        if REDRAW.borrow(cs).replace(false) {
+            RINGING.borrow_ref(cs).clone()
+        } else {
+            None
+        }
+    });
+    if let Some(ringing) = ringing {
+        闪烁(&ringing);
+    }
+}
+
+fn 闪烁(ringing: &Ringing) {
+    match &ringing.lamp {
+        Some(position) => {
+            // 灯组可能在闹钟设好之后换过，找不到灯时退回整屏闪烁
+            if screen::灯闪烁(ringing.color, position, ringing.on).is_err() {
+                screen::整屏闪烁(ringing.color, ringing.on);
+            }
+        }
+        None => screen::整屏闪烁(ringing.color, ringing.on),
+    }
+}
+
+/// 确认正在响的闹钟，恢复原来的显示，返回是否真的有闹钟在响
+pub fn 确认闹钟() -> bool {
+    let ringing = critical_section::with(|cs| {
+        REDRAW.borrow(cs).set(false);
+        RINGING.borrow_ref_mut(cs).take()
+    });
+    match ringing {
+        Some(mut ringing) => {
+            // 不管屏幕上最后画的是亮还是灭，都按灭的状态画一次，恢复原来的显示
+            ringing.on = false;
+            闪烁(&ringing);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,194 @@
+//! 把时钟备份在RTC快速内存里，panic或者看门狗复位之后内容还在，
+//! 重启时优先用它恢复时间，而不是编译时写进去的时间
+
+use crate::alarm::{Alarm, ALARMS, MAX_ALARMS};
+use crate::calendar::{DstRule, Zone};
+use crate::command::Position;
+use crate::node::{self, Node};
+use crate::time::{self, Reference};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use critical_section::{CriticalSection, Mutex};
+use embedded_graphics::pixelcolor::{
+    raw::{RawData, RawU16},
+    Rgb565,
+};
+use esp_hal::{macros::ram, rtc_cntl::Rtc, systimer::SystemTimer};
+
+/// 备份开头的标记，改了备份的格式就换一个
+const MAGIC: u32 = 0x4c43_4b34;
+
+/// 每个闹钟占的字节：秒数、颜色、灯的位置、最后一次响的那天
+const ALARM_LEN: usize = 4 + 2 + 1 + 4;
+
+/// 闹钟从这里开始，前面是节点地址、所在的组、是否中继和闹钟的个数
+const ALARMS_AT: usize = 4 + 8 + 8 + 4 + 4 + 1 + 2 + 4 + 1 + 1;
+
+const LEN: usize = ALARMS_AT + ALARM_LEN * MAX_ALARMS + 4;
+
+/// 复位后不会被清零的内存，上电时是随机内容，靠MAGIC和CRC判断是否有效
+#[ram(rtc_fast, uninitialized)]
+static mut BACKUP: [u8; LEN] = [0; LEN];
+
+/// RTC定时器复位后接着走，用来估计重启花了多久
+pub static RTC: Mutex<RefCell<Option<Rtc<'static>>>> = Mutex::new(RefCell::new(None));
+
+/// 备份的内容
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// 备份时的UTC毫秒数
+    pub utc_ms: i64,
+    /// 备份时RTC定时器的微秒数
+    pub rtc_us: u64,
+    /// 晶振的修正值
+    pub ppb: i32,
+    pub zone: Zone,
+    pub node: Node,
+    pub alarms: Vec<Alarm>,
+}
+
+/// 闹钟灯的位置用一个字节存：0xff整屏，0xfe/0xfd/0xfc是left/middle/right，其它是灯的序号
+pub fn encode_lamp(lamp: &Option<Position>) -> u8 {
+    match lamp {
+        None => 0xff,
+        Some(Position::Left) => 0xfe,
+        Some(Position::Middle) => 0xfd,
+        Some(Position::Right) => 0xfc,
+        Some(Position::Index(index)) => (*index).min(0xfb) as u8,
+    }
+}
+
+pub fn decode_lamp(byte: u8) -> Option<Position> {
+    match byte {
+        0xff => None,
+        0xfe => Some(Position::Left),
+        0xfd => Some(Position::Middle),
+        0xfc => Some(Position::Right),
+        index => Some(Position::Index(index as usize)),
+    }
+}
+
+impl Backup {
+    fn encode(&self) -> [u8; LEN] {
+        let mut bytes = [0; LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.utc_ms.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.rtc_us.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.ppb.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.zone.offset.to_le_bytes());
+        bytes[28] = match self.zone.dst {
+            DstRule::None => 0,
+            DstRule::Eu => 1,
+            DstRule::Us => 2,
+        };
+        bytes[29..31].copy_from_slice(&self.node.address.to_le_bytes());
+        bytes[31..35].copy_from_slice(&self.node.groups.to_le_bytes());
+        bytes[35] = self.node.relay as u8;
+        bytes[36] = self.alarms.len().min(MAX_ALARMS) as u8;
+        for (alarm, chunk) in self
+            .alarms
+            .iter()
+            .zip(bytes[ALARMS_AT..LEN - 4].as_chunks_mut::<ALARM_LEN>().0)
+        {
+            chunk[0..4].copy_from_slice(&alarm.secs_of_day.to_le_bytes());
+            chunk[4..6].copy_from_slice(&RawU16::from(alarm.color).into_inner().to_le_bytes());
+            chunk[6] = encode_lamp(&alarm.lamp);
+            chunk[7..11].copy_from_slice(&alarm.fired_day.to_le_bytes());
+        }
+        let crc = crc32(&bytes[..LEN - 4]);
+        bytes[LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; LEN]) -> Option<Backup> {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(LEN - 4) != crc32(&bytes[..LEN - 4]) {
+            return None;
+        }
+        let dst = match bytes[28] {
+            0 => DstRule::None,
+            1 => DstRule::Eu,
+            2 => DstRule::Us,
+            _ => return None,
+        };
+        let count = bytes[36] as usize;
+        if count > MAX_ALARMS {
+            return None;
+        }
+        let alarms = bytes[ALARMS_AT..LEN - 4]
+            .as_chunks::<ALARM_LEN>()
+            .0
+            .iter()
+            .take(count)
+            .map(|chunk| Alarm {
+                secs_of_day: u32::from_le_bytes(chunk[0..4].try_into().unwrap()),
+                color: Rgb565::from(RawU16::new(u16::from_le_bytes([chunk[4], chunk[5]]))),
+                lamp: decode_lamp(chunk[6]),
+                fired_day: i32::from_le_bytes(chunk[7..11].try_into().unwrap()),
+            })
+            .collect();
+        Some(Backup {
+            utc_ms: i64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            rtc_us: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+            ppb: word(20) as i32,
+            zone: Zone {
+                offset: word(24) as i32,
+                dst,
+            },
+            node: Node {
+                address: u16::from_le_bytes([bytes[29], bytes[30]]),
+                groups: word(31),
+                relay: bytes[35] != 0,
+            },
+            alarms,
+        })
+    }
+}
+
+/// CRC-32（IEEE 802.3），数据很短，逐位算就够了
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// 把现在的时间、修正值、时区、节点地址和闹钟写进备份，时钟中断每次都调用
+pub fn 保存时钟(cs: CriticalSection) {
+    let Some(rtc_us) = RTC.borrow_ref(cs).as_ref().map(|rtc| rtc.get_time_us()) else {
+        return;
+    };
+    let now: Reference = time::NOW.borrow(cs).get();
+    let backup = Backup {
+        utc_ms: now.utc_ms_at(SystemTimer::now()),
+        rtc_us,
+        ppb: now.ppb,
+        zone: time::ZONE.borrow(cs).get(),
+        node: node::NODE.borrow(cs).get(),
+        alarms: ALARMS.borrow_ref(cs).clone(),
+    };
+    unsafe {
+        BACKUP = backup.encode();
+    }
+}
+
+/// 读出有效的备份，并把UTC毫秒数加上重启花掉的时间，没有有效备份时返回None
+pub fn 读取时钟(cs: CriticalSection) -> Option<Backup> {
+    let rtc_us = RTC.borrow_ref(cs).as_ref()?.get_time_us();
+    let backup = Backup::decode(unsafe { &*core::ptr::addr_of!(BACKUP) })?;
+    // 断过电的话RTC定时器会从零开始，这时备份不可信
+    let elapsed = rtc_us.checked_sub(backup.rtc_us)?;
+    Some(Backup {
+        utc_ms: backup.utc_ms + (elapsed / 1000) as i64,
+        rtc_us,
+        ..backup
+    })
+}
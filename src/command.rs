@@ -1,34 +1,205 @@
-use alloc::{collections::VecDeque, string::String, vec::Vec};
-use core::cell::RefCell;
+use crate::ack::{Retry, Seen};
+use crate::calendar::{parse_iso, parse_millis, parse_offset, Date, DstRule, Zone};
+use crate::lamp::{Lamp, Layout, DEFAULT_RADIUS, MAX_LAMPS, MAX_RADIUS};
+use crate::lora;
+use crate::mode;
+use crate::node;
+use crate::relay::Recent;
+use crate::schedule::{Scheduler, Trigger, MAX_DELAY_SECS};
+use crate::screen::{BOTTOM_Y, RIGHT_X};
+use crate::sync::{self, Role};
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
+use core::cell::{Cell, RefCell};
 use critical_section::Mutex;
-use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+use embedded_graphics::{
+    pixelcolor::{raw::RawU16, Rgb565},
+    prelude::Point,
+};
+use esp_hal::systimer::SystemTimer;
 
-pub static COMMAND_BUCKET: Mutex<RefCell<Option<VecDeque<Command>>>> =
-    Mutex::new(RefCell::new(None));
-pub static DELAY_BUCKET: Mutex<RefCell<Option<VecDeque<usize>>>> = Mutex::new(RefCell::new(None));
+/// 等待执行的延时命令，按截止时间排序
+pub static SCHEDULER: Mutex<RefCell<Option<Scheduler<Command>>>> = Mutex::new(RefCell::new(None));
+/// 已经到期、等待主循环执行的命令
+pub static READY: Mutex<RefCell<Option<VecDeque<Command>>>> = Mutex::new(RefCell::new(None));
+/// 最近执行过的带序号命令，重发过来的不再执行
+pub static SEEN: Mutex<RefCell<Seen>> = Mutex::new(RefCell::new(Seen::new(
+    SEEN_SECS * SystemTimer::TICKS_PER_SECOND,
+)));
+/// 用send发出去、还在等ACK的命令，发给哪个节点，以及不带序号的原文
+pub static OUTBOX: Mutex<RefCell<Vec<(Retry, u16, String)>>> = Mutex::new(RefCell::new(Vec::new()));
+/// 最近见过的带跳数的消息，见[`relay`](crate::relay)
+pub static RECENT: Mutex<RefCell<Recent>> = Mutex::new(RefCell::new(Recent::new(
+    RECENT_MS * SystemTimer::TICKS_PER_SECOND / 1000,
+)));
+/// 下一条send命令的序号，开机时换成随机数，免得重启后和对方还记着的序号撞上
+pub static NEXT_SEND_ID: Mutex<Cell<u16>> = Mutex::new(Cell::new(1));
 
-#[derive(Debug)]
+/// 带跳数的消息记多少毫秒，只要盖住同一条消息从最长的路径绕过来的时间：每跳按半秒算，转满
+/// MAX_TTL跳也不到4秒。记得再久的话，过一会儿再发的同样内容也会被当成重复的挡掉
+pub const RECENT_MS: u64 = 4000;
+/// 带序号的命令记多少秒，要比send命令所有重发加起来的时间长
+pub const SEEN_SECS: u64 = 60;
+/// send命令第一次等回复的秒数，之后每次翻倍
+pub const SEND_TIMEOUT_SECS: u64 = 2;
+/// send命令最多发几次
+pub const MAX_SENDS: u8 = 4;
+
+#[derive(Debug, Clone)]
 pub enum Command {
     Ping,
     /// 前者颜色，后者指定灯的位置，(命令格式：@color,position)
     Blink(Rgb565, Position),
-    /// 命令格式：#color,position,time
-    DelayBlink(Rgb565, Position, usize),
     /// 重新初始化屏幕
     Reload,
+    /// 在屏幕上方显示一行文字，(命令格式：msg text)
+    Message(String),
+    /// 若干秒之后再执行后面的命令，最长一年，(命令格式：after secs command)
+    /// `#color,position,time`是`after time @color,position`的简写
+    After(u32, Box<Command>),
+    /// 在一天中的某个时刻执行后面的命令，时刻已经过了就等到第二天，(命令格式：at HH:MM[:SS] command)
+    At(u32, Box<Command>),
+    /// 周期执行后面的命令，周期最长一年，可以用xN限定总共执行几次，
+    /// (命令格式：every period[s|m|h] [xN] command / every day HH:MM[:SS] [xN] command)
+    Every(Trigger, Option<u32>, Box<Command>),
+    /// 重新排布灯组，格式见[`Layout`]
+    Lamps(Layout),
+    /// 在运行时校准时钟，只给时间时日期不变，没写UTC偏移时按当地时间理解，时间是从零点开始的毫秒数，
+    /// (命令格式：time HH:MM[:SS[.mmm]] / time YYYY-MM-DDTHH:MM:SS[.mmm][±HH:MM])
+    SetTime(Option<Date>, u32, Option<i32>),
+    /// 设置时区，不带参数时回报当前时区，(命令格式：tz [±HH:MM [eu|us]])
+    TimeZone(Option<Zone>),
+    /// 校准晶振，`cal start`之后的两次设置时间（至少隔五分钟）用来计算误差，`cal clear`清掉修正值，
+    /// 不带参数时回报当前的修正值，(命令格式：cal [start|clear])
+    Calibrate(Option<bool>),
+    /// 设置时间同步的角色，不带参数时回报当前角色和最近一次的偏差，master广播至少隔十秒，
+    /// (命令格式：sync [master [secs]|follow|off])
+    Sync(Option<Role>),
+    /// master广播的UTC毫秒数，(命令格式：tsync ms)
+    TimeBroadcast(i64),
+    /// follower发来的对时请求，带着它的本地计时，(命令格式：treq local)
+    TimeRequest(i64),
+    /// master对请求的回复，(命令格式：tresp local ms)
+    TimeReply(i64, i64),
+    /// follower对时之后回报的偏差毫秒数，(命令格式：toff ms)
+    TimeOffset(i64),
+    /// 数字区切换成倒计时，到零时可以点亮一个灯，之后回到时钟，最长99:59:59，
+    /// (命令格式：countdown [HH:]MM:SS [color,position] / countdown pause|resume|cancel)
+    Countdown(Countdown),
+    /// 数字区切换成秒表，记圈时通过串口回报这一圈和总共的时间，reset后回到时钟，
+    /// (命令格式：stopwatch start|stop|lap|reset)
+    Stopwatch(Stopwatch),
+    /// 每天的闹钟，到点后整屏（或者指定的灯）按颜色闪烁，直到确认，
+    /// (命令格式：alarm HH:MM[:SS] color[,position])
+    Alarm(u32, Rgb565, Option<Position>),
+    /// 列出所有闹钟，(命令格式：alarms)
+    Alarms,
+    /// 按序号删除闹钟，(命令格式：alarm del index)
+    RemoveAlarm(usize),
+    /// 确认正在响的闹钟，也可以按BOOT键，(命令格式：ack)
+    Ack,
+    /// 修改时钟的显示方式，不带参数时回报当前设置，格式见[`ClockFormat`](crate::screen::ClockFormat)
+    Clock(Option<ClockOption>),
+    /// 读写LoRa模块的参数，不带参数时回报模块当前的参数，
+    /// (命令格式：lora [addr n|chan n|rate bps|power 0-3|mode transparent|model e32|e22])
+    Lora(Option<LoraSetting>),
+    /// 读写本节点的地址，不带参数时回报地址和所在的组，(命令格式：addr [n])
+    Address(Option<u16>),
+    /// 加入或者退出一个组，之后`gN:`开头的命令本节点也会执行，(命令格式：join n|leave n)
+    Group(u8, bool),
+    /// 打开或者关闭中继，不带参数时和addr一样回报节点状态，(命令格式：relay [on|off])
+    Relay(Option<bool>),
+    /// 列出所有等待执行的定时命令，(命令格式：jobs)
+    Jobs,
+    /// 按编号取消一个定时命令，(命令格式：cancel id)
+    Cancel(u32),
+    /// 清空所有定时命令，(命令格式：clear)
+    Clear,
+    /// 带序号把一条命令发给另一个节点，等不到这个节点的ACK会重发几次，结果通过串口回报，
+    /// 带着目标地址和整行，(命令格式：send 地址[~跳数]:命令)
+    Send(u16, String),
+}
+
+/// LoRa模块参数里可以单独修改的一项
+#[derive(Debug, Clone)]
+pub enum LoraSetting {
+    Address(u16),
+    Channel(u8),
+    AirRate(u32),
+    Power(u8),
+    /// 切回透明传输。定点传输要在每条消息前面加三字节的地址和信道，固件没有做，所以不能切过去
+    Transparent,
+    /// 不是模块的参数，换成另一种型号的模块时用
+    Model(lora::Model),
+}
+
+/// 时钟显示方式里可以单独修改的一项
+#[derive(Debug, Clone)]
+pub enum ClockOption {
+    Hour12(bool),
+    Blink(bool),
+    Seconds(bool),
+}
+
+/// 倒计时命令的几种动作
+#[derive(Debug, Clone)]
+pub enum Countdown {
+    /// 秒数，以及到零时要点亮的灯
+    Start(u32, Option<(Rgb565, Position)>),
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// 秒表命令的几种动作
+#[derive(Debug, Clone)]
+pub enum Stopwatch {
+    Start,
+    Stop,
+    Lap,
+    Reset,
 }
 
+/// 灯的位置，除了left、middle、right之外也可以直接写灯的序号（从0开始）
 #[derive(Debug, Clone)]
 pub enum Position {
     Left,
     Right,
     Middle,
+    Index(usize),
 }
 
 #[derive(Debug)]
 pub enum CommandErr {
     FaillToParse,
     InvalidString,
+    /// 灯组里没有这个位置的灯
+    NoSuchLamp,
+    /// 要操作的倒计时、秒表、闹钟或者定时命令不存在
+    NotFound,
+    /// 闹钟已经满了
+    Full,
+    /// 没有接LoRa模块，或者模块出错
+    Lora,
+    /// 改动已经生效，但写flash出错没有保存
+    NotSaved,
+    /// 时间设好了，但校准失败
+    Calibration,
+}
+
+impl CommandErr {
+    /// NAK回复里的错误码
+    pub fn code(&self) -> u8 {
+        match self {
+            CommandErr::FaillToParse => 1,
+            CommandErr::InvalidString => 2,
+            CommandErr::NoSuchLamp => 3,
+            CommandErr::NotFound => 4,
+            CommandErr::Full => 5,
+            CommandErr::Lora => 6,
+            CommandErr::NotSaved => 7,
+            CommandErr::Calibration => 8,
+        }
+    }
 }
 
 impl TryFrom<&Vec<char>> for Command {
@@ -54,68 +225,557 @@ impl TryFrom<&str> for Command {
                     Err(CommandErr::InvalidString)
                 }
             }
+            "a" => {
+                if let Some(rest) = value.strip_prefix("after ") {
+                    let (secs, command) = rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                    let secs = parse_delay(secs)?;
+                    Ok(Command::After(
+                        secs,
+                        Box::new(Command::try_from(command.trim())?),
+                    ))
+                } else if value == "addr" {
+                    Ok(Command::Address(None))
+                } else if let Some(address) = value.strip_prefix("addr ") {
+                    Ok(Command::Address(Some(parse_number(Some(address))?)))
+                } else if value == "alarms" {
+                    Ok(Command::Alarms)
+                } else if value == "ack" {
+                    Ok(Command::Ack)
+                } else if let Some(index) = value.strip_prefix("alarm del ") {
+                    Ok(Command::RemoveAlarm(parse_number(Some(index))?))
+                } else if let Some(rest) = value.strip_prefix("alarm ") {
+                    let (at, target) = rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                    let args = split_args(target.trim());
+                    let color = parse_color(args.first().ok_or(CommandErr::FaillToParse)?)?;
+                    let lamp = match args.get(1) {
+                        Some(position) => Some(parse_position(position)?),
+                        None => None,
+                    };
+                    Ok(Command::Alarm(parse_clock(at)?, color, lamp))
+                } else if let Some(rest) = value.strip_prefix("at ") {
+                    let (at, command) = rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                    let at = parse_clock(at)?;
+                    Ok(Command::At(
+                        at,
+                        Box::new(Command::try_from(command.trim())?),
+                    ))
+                } else {
+                    Err(CommandErr::InvalidString)
+                }
+            }
+            "e" => match value.strip_prefix("every ") {
+                Some(rest) => {
+                    let (trigger, rest) = match rest.strip_prefix("day ") {
+                        Some(rest) => {
+                            let (at, rest) =
+                                rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                            (Trigger::Daily(parse_clock(at)?), rest)
+                        }
+                        None => {
+                            let (period, rest) =
+                                rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                            (Trigger::Every(parse_period(period)?), rest)
+                        }
+                    };
+                    let (repeat, command) = match rest.strip_prefix('x') {
+                        Some(rest) => {
+                            let (count, command) =
+                                rest.split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                            (Some(parse_number(Some(count))?), command)
+                        }
+                        None => (None, rest),
+                    };
+                    if repeat == Some(0) {
+                        return Err(CommandErr::FaillToParse);
+                    }
+                    Ok(Command::Every(
+                        trigger,
+                        repeat,
+                        Box::new(Command::try_from(command.trim())?),
+                    ))
+                }
+                None => Err(CommandErr::InvalidString),
+            },
+            "j" => {
+                if value == "jobs" {
+                    Ok(Command::Jobs)
+                } else if let Some(group) = value.strip_prefix("join ") {
+                    Ok(Command::Group(parse_group(group)?, true))
+                } else {
+                    Err(CommandErr::InvalidString)
+                }
+            }
+            "c" => {
+                if value == "clear" {
+                    Ok(Command::Clear)
+                } else if value == "clock" {
+                    Ok(Command::Clock(None))
+                } else if let Some(rest) = value.strip_prefix("clock ") {
+                    let on_off = |value: &str| match value {
+                        "on" => Ok(true),
+                        "off" => Ok(false),
+                        _ => Err(CommandErr::FaillToParse),
+                    };
+                    let option = match rest.split_once(' ') {
+                        None if rest == "12" => ClockOption::Hour12(true),
+                        None if rest == "24" => ClockOption::Hour12(false),
+                        Some(("blink", value)) => ClockOption::Blink(on_off(value)?),
+                        Some(("seconds", value)) => ClockOption::Seconds(on_off(value)?),
+                        _ => return Err(CommandErr::FaillToParse),
+                    };
+                    Ok(Command::Clock(Some(option)))
+                } else if let Some(rest) = value.strip_prefix("countdown ") {
+                    match rest {
+                        "pause" => Ok(Command::Countdown(Countdown::Pause)),
+                        "resume" => Ok(Command::Countdown(Countdown::Resume)),
+                        "cancel" => Ok(Command::Countdown(Countdown::Cancel)),
+                        _ => {
+                            let (time, lamp) = match rest.split_once(' ') {
+                                Some((time, lamp)) => (time, Some(lamp)),
+                                None => (rest, None),
+                            };
+                            let lamp = match lamp {
+                                Some(lamp) => match lamp.split_once(',') {
+                                    Some((color, position)) => {
+                                        Some((parse_color(color)?, parse_position(position)?))
+                                    }
+                                    None => return Err(CommandErr::FaillToParse),
+                                },
+                                None => None,
+                            };
+                            Ok(Command::Countdown(Countdown::Start(
+                                parse_duration(time)?,
+                                lamp,
+                            )))
+                        }
+                    }
+                } else if value == "cal" {
+                    Ok(Command::Calibrate(None))
+                } else if value == "cal start" {
+                    Ok(Command::Calibrate(Some(true)))
+                } else if value == "cal clear" {
+                    Ok(Command::Calibrate(Some(false)))
+                } else if let Some(id) = value.strip_prefix("cancel ") {
+                    Ok(Command::Cancel(parse_number(Some(id))?))
+                } else {
+                    Err(CommandErr::InvalidString)
+                }
+            }
+            "t" if value.starts_with("tsync ") => {
+                Ok(Command::TimeBroadcast(parse_number(value.get(6..))?))
+            }
+            "t" if value.starts_with("treq ") => {
+                Ok(Command::TimeRequest(parse_number(value.get(5..))?))
+            }
+            "t" if value.starts_with("tresp ") => {
+                let (sent, master) = value[6..].split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                Ok(Command::TimeReply(
+                    parse_number(Some(sent))?,
+                    parse_number(Some(master))?,
+                ))
+            }
+            "t" if value.starts_with("toff ") => {
+                Ok(Command::TimeOffset(parse_number(value.get(5..))?))
+            }
+            "t" => match value.strip_prefix("time ") {
+                Some(time) if time.contains('-') => match parse_iso(time) {
+                    Some((date, ms, offset)) => Ok(Command::SetTime(Some(date), ms, offset)),
+                    None => Err(CommandErr::FaillToParse),
+                },
+                Some(time) => Ok(Command::SetTime(None, parse_clock_ms(time)?, None)),
+                None if value == "tz" => Ok(Command::TimeZone(None)),
+                None => match value.strip_prefix("tz ") {
+                    Some(zone) => Ok(Command::TimeZone(Some(parse_zone(zone)?))),
+                    None => Err(CommandErr::InvalidString),
+                },
+            },
+            "m" => match value.strip_prefix("msg ") {
+                Some(text) => Ok(Command::Message(text.into())),
+                None => Err(CommandErr::InvalidString),
+            },
+            "l" if value == "lora" => Ok(Command::Lora(None)),
+            "l" if value.starts_with("lora ") => {
+                let (key, value) = value[5..].split_once(' ').ok_or(CommandErr::FaillToParse)?;
+                let setting = match key {
+                    "addr" => LoraSetting::Address(parse_number(Some(value))?),
+                    "chan" => LoraSetting::Channel(parse_number(Some(value))?),
+                    "rate" => LoraSetting::AirRate(parse_number(Some(value))?),
+                    "power" => LoraSetting::Power(parse_number(Some(value))?),
+                    "mode" if value == "transparent" => LoraSetting::Transparent,
+                    "model" if value == "e32" => LoraSetting::Model(lora::Model::E32),
+                    "model" if value == "e22" => LoraSetting::Model(lora::Model::E22),
+                    _ => return Err(CommandErr::FaillToParse),
+                };
+                Ok(Command::Lora(Some(setting)))
+            }
+            "l" if value.starts_with("leave ") => {
+                Ok(Command::Group(parse_group(&value[6..])?, false))
+            }
+            "l" => match value.strip_prefix("lamps ") {
+                Some(layout) => Ok(Command::Lamps(parse_layout(layout)?)),
+                None => Err(CommandErr::InvalidString),
+            },
+            "s" if value.starts_with("send ") => match value[5..].trim() {
+                "" => Err(CommandErr::FaillToParse),
+                line => match node::拆分目标(line) {
+                    (Some(node::Target::Node(to)), command) if !command.is_empty() => {
+                        Ok(Command::Send(to, line.into()))
+                    }
+                    _ => Err(CommandErr::InvalidString),
+                },
+            },
+            "s" => match value {
+                "sync" => Ok(Command::Sync(None)),
+                "sync master" => Ok(Command::Sync(Some(Role::Master(sync::DEFAULT_PERIOD)))),
+                "sync follow" => Ok(Command::Sync(Some(Role::Follower))),
+                "sync off" => Ok(Command::Sync(Some(Role::Off))),
+                "stopwatch start" => Ok(Command::Stopwatch(Stopwatch::Start)),
+                "stopwatch stop" => Ok(Command::Stopwatch(Stopwatch::Stop)),
+                "stopwatch lap" => Ok(Command::Stopwatch(Stopwatch::Lap)),
+                "stopwatch reset" => Ok(Command::Stopwatch(Stopwatch::Reset)),
+                _ => match value.strip_prefix("sync master ") {
+                    Some(period) => match parse_number(Some(period))? {
+                        period if period >= sync::MIN_PERIOD => {
+                            Ok(Command::Sync(Some(Role::Master(period))))
+                        }
+                        _ => Err(CommandErr::FaillToParse),
+                    },
+                    None => Err(CommandErr::InvalidString),
+                },
+            },
             "r" => {
                 if value == "reload" {
                     Ok(Command::Reload)
+                } else if value == "relay" {
+                    Ok(Command::Relay(None))
+                } else if value == "relay on" {
+                    Ok(Command::Relay(Some(true)))
+                } else if value == "relay off" {
+                    Ok(Command::Relay(Some(false)))
                 } else {
                     Err(CommandErr::InvalidString)
                 }
             }
             "@" => {
-                let mut iter = value[1..].split(',');
+                let args = split_args(&value[1..]);
+                let mut iter = args.iter();
                 let color = match iter.next() {
-                    Some(color) => match color {
-                        "red" => Ok(Rgb565::RED),
-                        "green" => Ok(Rgb565::GREEN),
-                        "blue" => Ok(Rgb565::BLUE),
-                        _ => Err(CommandErr::FaillToParse),
-                    },
+                    Some(color) => parse_color(color),
                     None => Err(CommandErr::FaillToParse),
                 }?;
 
                 let position = match iter.next() {
-                    Some(position) => match position {
-                        "left" => Ok(Position::Left),
-                        "right" => Ok(Position::Right),
-                        "middle" => Ok(Position::Middle),
-                        _ => Err(CommandErr::FaillToParse),
-                    },
+                    Some(position) => parse_position(position),
                     None => Err(CommandErr::FaillToParse),
                 }?;
                 Ok(Command::Blink(color, position))
             }
             "#" => {
-                let mut iter = value[1..].split(',');
+                let args = split_args(&value[1..]);
+                let mut iter = args.iter();
                 let color = match iter.next() {
-                    Some(color) => match color {
-                        "red" => Ok(Rgb565::RED),
-                        "green" => Ok(Rgb565::GREEN),
-                        "blue" => Ok(Rgb565::BLUE),
-                        _ => Err(CommandErr::FaillToParse),
-                    },
+                    Some(color) => parse_color(color),
                     None => Err(CommandErr::FaillToParse),
                 }?;
 
                 let position = match iter.next() {
-                    Some(position) => match position {
-                        "left" => Ok(Position::Left),
-                        "right" => Ok(Position::Right),
-                        "middle" => Ok(Position::Middle),
-                        _ => Err(CommandErr::FaillToParse),
-                    },
+                    Some(position) => parse_position(position),
                     None => Err(CommandErr::FaillToParse),
                 }?;
 
                 let delay = match iter.next() {
-                    Some(delay) => match delay.parse::<u32>() {
-                        Ok(delay) => Ok(delay),
-                        Err(_) => Err(CommandErr::FaillToParse),
-                    },
+                    Some(delay) => parse_delay(delay),
                     None => Err(CommandErr::FaillToParse),
                 }?;
-                Ok(Command::DelayBlink(color, position, delay as usize))
+                Ok(Command::After(
+                    delay,
+                    Box::new(Command::Blink(color, position)),
+                ))
             }
             _ => Err(CommandErr::InvalidString),
         }
     }
 }
+
+/// 命名颜色表，值为24位RGB
+const PALETTE: [(&str, (u8, u8, u8)); 15] = [
+    ("red", (255, 0, 0)),
+    ("green", (0, 255, 0)),
+    ("blue", (0, 0, 255)),
+    ("orange", (255, 165, 0)),
+    ("yellow", (255, 255, 0)),
+    ("purple", (128, 0, 128)),
+    ("magenta", (255, 0, 255)),
+    ("pink", (255, 192, 203)),
+    ("cyan", (0, 255, 255)),
+    ("white", (255, 255, 255)),
+    ("gray", (128, 128, 128)),
+    ("grey", (128, 128, 128)),
+    ("black", (0, 0, 0)),
+    // off表示把灯关掉，也就是涂成背景色
+    ("off", (0, 0, 0)),
+    ("amber", (255, 191, 0)),
+];
+
+/// 按逗号切分参数，但忽略括号里的逗号，保证`rgb(r,g,b)`是一个整体
+fn split_args(value: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&value[start..]);
+    args
+}
+
+/// 把8位的通道值按比例缩放到`bits`位，四舍五入
+fn scale_channel(value: u8, bits: u32) -> u8 {
+    let max = (1u16 << bits) - 1;
+    ((value as u16 * max + 127) / 255) as u8
+}
+
+fn rgb888(r: u8, g: u8, b: u8) -> Rgb565 {
+    Rgb565::new(
+        scale_channel(r, 5),
+        scale_channel(g, 6),
+        scale_channel(b, 5),
+    )
+}
+
+/// 解析颜色，支持命名颜色、`#RRGGBB`、原始的`0xRRRR`(RGB565)以及`rgb(r,g,b)`
+fn parse_color(value: &str) -> Result<Rgb565, CommandErr> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return Err(CommandErr::FaillToParse);
+        }
+        let rgb = u32::from_str_radix(hex, 16).map_err(|_| CommandErr::FaillToParse)?;
+        return Ok(rgb888((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+    if let Some(raw) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        if raw.is_empty() || raw.len() > 4 {
+            return Err(CommandErr::FaillToParse);
+        }
+        let raw = u16::from_str_radix(raw, 16).map_err(|_| CommandErr::FaillToParse)?;
+        return Ok(Rgb565::from(RawU16::new(raw)));
+    }
+    if let Some(triple) = value
+        .strip_prefix("rgb(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        let mut channels = triple.split(',').map(|c| c.trim().parse::<u8>());
+        return match (
+            channels.next(),
+            channels.next(),
+            channels.next(),
+            channels.next(),
+        ) {
+            (Some(Ok(r)), Some(Ok(g)), Some(Ok(b)), None) => Ok(rgb888(r, g, b)),
+            _ => Err(CommandErr::FaillToParse),
+        };
+    }
+    match PALETTE
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+    {
+        Some((_, (r, g, b))) => Ok(rgb888(*r, *g, *b)),
+        None => Err(CommandErr::FaillToParse),
+    }
+}
+
+fn parse_position(value: &str) -> Result<Position, CommandErr> {
+    match value.trim() {
+        "left" => Ok(Position::Left),
+        "right" => Ok(Position::Right),
+        "middle" => Ok(Position::Middle),
+        index => match index.parse::<usize>() {
+            Ok(index) => Ok(Position::Index(index)),
+            Err(_) => Err(CommandErr::FaillToParse),
+        },
+    }
+}
+
+/// 组号，超出范围的不接受
+fn parse_group(value: &str) -> Result<u8, CommandErr> {
+    let group = parse_number(Some(value))?;
+    if group > node::MAX_GROUP {
+        return Err(CommandErr::FaillToParse);
+    }
+    Ok(group)
+}
+
+fn parse_number<T: core::str::FromStr>(value: Option<&str>) -> Result<T, CommandErr> {
+    match value {
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| CommandErr::FaillToParse),
+        None => Err(CommandErr::FaillToParse),
+    }
+}
+
+/// 解析倒计时的时长`MM:SS`或者`HH:MM:SS`，分钟数可以超过59，总共不能超过99:59:59
+fn parse_duration(value: &str) -> Result<u32, CommandErr> {
+    let parts: Vec<u32> = value
+        .split(':')
+        .map(|part| parse_number(Some(part)))
+        .collect::<Result<_, _>>()?;
+    let secs = match parts[..] {
+        [min, sec] if sec < 60 => min.checked_mul(60).and_then(|min| min.checked_add(sec)),
+        [hour, min, sec] if min < 60 && sec < 60 => hour
+            .checked_mul(3600)
+            .and_then(|hour| hour.checked_add(min * 60 + sec)),
+        _ => None,
+    };
+    secs.filter(|secs| *secs <= mode::MAX_COUNTDOWN_SECS)
+        .ok_or(CommandErr::FaillToParse)
+}
+
+/// 解析`HH:MM[:SS]`，返回从零点开始的秒数
+fn parse_clock(value: &str) -> Result<u32, CommandErr> {
+    let mut iter = value.trim().split(':');
+    let hour: u32 = parse_number(iter.next())?;
+    let min: u32 = parse_number(iter.next())?;
+    let sec: u32 = match iter.next() {
+        Some(sec) => parse_number(Some(sec))?,
+        None => 0,
+    };
+    if hour >= 24 || min >= 60 || sec >= 60 || iter.next().is_some() {
+        return Err(CommandErr::FaillToParse);
+    }
+    Ok(hour * 3600 + min * 60 + sec)
+}
+
+/// 解析`HH:MM[:SS[.mmm]]`，返回从零点开始的毫秒数
+fn parse_clock_ms(value: &str) -> Result<u32, CommandErr> {
+    let (clock, millis) = match value.trim().split_once('.') {
+        Some((clock, fraction)) => (
+            clock,
+            parse_millis(fraction).ok_or(CommandErr::FaillToParse)?,
+        ),
+        None => (value, 0),
+    };
+    Ok(parse_clock(clock)? * 1000 + millis)
+}
+
+fn parse_zone(value: &str) -> Result<Zone, CommandErr> {
+    let mut iter = value.split_whitespace();
+    let offset = match iter.next() {
+        Some("utc") => Some(0),
+        Some(offset) => parse_offset(offset),
+        None => None,
+    }
+    .ok_or(CommandErr::FaillToParse)?;
+    let dst = match iter.next() {
+        None => DstRule::None,
+        Some("eu") => DstRule::Eu,
+        Some("us") => DstRule::Us,
+        Some(_) => return Err(CommandErr::FaillToParse),
+    };
+    match iter.next() {
+        Some(_) => Err(CommandErr::FaillToParse),
+        None => Ok(Zone { offset, dst }),
+    }
+}
+
+/// 解析周期，可以带单位s、m、h，不带单位时按秒算
+fn parse_period(value: &str) -> Result<u32, CommandErr> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 3600),
+        _ => (value, 1),
+    };
+    let period: u32 = parse_number(Some(number))?;
+    match period.checked_mul(unit) {
+        Some(period) if period > 0 && period <= MAX_DELAY_SECS => Ok(period),
+        _ => Err(CommandErr::FaillToParse),
+    }
+}
+
+/// 延时的秒数，最长一年
+fn parse_delay(value: &str) -> Result<u32, CommandErr> {
+    match parse_number(Some(value))? {
+        secs if secs <= MAX_DELAY_SECS => Ok(secs),
+        _ => Err(CommandErr::FaillToParse),
+    }
+}
+
+/// 半径可以省略，省略时使用默认值
+fn parse_radius(value: Option<&str>) -> Result<u32, CommandErr> {
+    match value {
+        Some(_) => match parse_number(value)? {
+            radius if radius <= MAX_RADIUS => Ok(radius),
+            _ => Err(CommandErr::FaillToParse),
+        },
+        None => Ok(DEFAULT_RADIUS),
+    }
+}
+
+/// 灯的坐标要在屏幕里面
+fn parse_coord(value: Option<&str>, max: u16) -> Result<i32, CommandErr> {
+    match parse_number(value)? {
+        coord if (0..=max as i32).contains(&coord) => Ok(coord),
+        _ => Err(CommandErr::FaillToParse),
+    }
+}
+
+fn parse_layout(value: &str) -> Result<Layout, CommandErr> {
+    let mut iter = value.split(',');
+    let layout = match iter.next() {
+        Some("row") => {
+            let count = parse_number(iter.next())?;
+            let radius = parse_radius(iter.next())?;
+            if !(1..=MAX_LAMPS).contains(&count) {
+                return Err(CommandErr::FaillToParse);
+            }
+            Layout::Row { count, radius }
+        }
+        Some("grid") => {
+            let cols: usize = parse_number(iter.next())?;
+            let rows = parse_number(iter.next())?;
+            let radius = parse_radius(iter.next())?;
+            match cols.checked_mul(rows) {
+                Some(count) if (1..=MAX_LAMPS).contains(&count) => {}
+                _ => return Err(CommandErr::FaillToParse),
+            }
+            Layout::Grid { cols, rows, radius }
+        }
+        Some("at") => {
+            let lamps = iter
+                .by_ref()
+                .map(|lamp| {
+                    let mut coord = lamp.split(':');
+                    let x = parse_coord(coord.next(), RIGHT_X)?;
+                    let y = parse_coord(coord.next(), BOTTOM_Y)?;
+                    let radius = parse_radius(coord.next())?;
+                    Ok(Lamp {
+                        center: Point::new(x, y),
+                        radius,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if !(1..=MAX_LAMPS).contains(&lamps.len()) {
+                return Err(CommandErr::FaillToParse);
+            }
+            Layout::Custom(lamps)
+        }
+        _ => return Err(CommandErr::FaillToParse),
+    };
+    match iter.next() {
+        Some(_) => Err(CommandErr::FaillToParse),
+        None => Ok(layout),
+    }
+}
@@ -0,0 +1,82 @@
+//! 直接调用ROM里的SPI flash函数读写一个扇区，存掉电之后也要保留的配置。
+//! 擦写flash时不能从flash取指令，所以调用都放在RAM里，并且关掉中断
+
+use esp_hal::macros::ram;
+
+/// 一个扇区的大小，擦除的最小单位
+pub const SECTOR_SIZE: usize = 4096;
+
+/// 默认分区表里nvs分区的开头，固件没有用NVS，借它的第一个扇区存闹钟
+pub const CONFIG_ADDR: u32 = 0x9000;
+
+/// 闹钟后面的一个扇区存晶振的修正值，两样东西分开擦写
+pub const CALIBRATION_ADDR: u32 = CONFIG_ADDR + SECTOR_SIZE as u32;
+
+#[derive(Debug)]
+pub enum FlashErr {
+    /// ROM函数返回了错误，错误码记在日志里
+    Rom,
+    /// 数据超过了一个扇区
+    TooLong,
+}
+
+// ESP32-C3 ROM里的函数地址，见esp32c3.rom.ld
+const ESP_ROM_SPIFLASH_ERASE_SECTOR: usize = 0x4000_0128;
+const ESP_ROM_SPIFLASH_WRITE: usize = 0x4000_012c;
+const ESP_ROM_SPIFLASH_READ: usize = 0x4000_0130;
+const ESP_ROM_SPIFLASH_UNLOCK: usize = 0x4000_0140;
+
+fn 检查(code: i32) -> Result<(), FlashErr> {
+    match code {
+        0 => Ok(()),
+        code => {
+            log::warn!("flash的ROM函数返回错误 {}", code);
+            Err(FlashErr::Rom)
+        }
+    }
+}
+
+#[ram]
+fn rom_read(addr: u32, data: *mut u32, len: u32) -> i32 {
+    let read: unsafe extern "C" fn(u32, *mut u32, u32) -> i32 =
+        unsafe { core::mem::transmute(ESP_ROM_SPIFLASH_READ) };
+    unsafe { read(addr, data, len) }
+}
+
+#[ram]
+fn rom_erase_and_write(addr: u32, data: *const u32, len: u32) -> i32 {
+    let unlock: unsafe extern "C" fn() -> i32 =
+        unsafe { core::mem::transmute(ESP_ROM_SPIFLASH_UNLOCK) };
+    let erase: unsafe extern "C" fn(u32) -> i32 =
+        unsafe { core::mem::transmute(ESP_ROM_SPIFLASH_ERASE_SECTOR) };
+    let write: unsafe extern "C" fn(u32, *const u32, u32) -> i32 =
+        unsafe { core::mem::transmute(ESP_ROM_SPIFLASH_WRITE) };
+    unsafe {
+        match unlock() {
+            0 => match erase(addr / SECTOR_SIZE as u32) {
+                0 => write(addr, data, len),
+                code => code,
+            },
+            code => code,
+        }
+    }
+}
+
+/// 从`addr`读出`N`个字（`N * 4`个字节）
+pub fn 读取<const N: usize>(addr: u32) -> Result<[u32; N], FlashErr> {
+    let mut words = [0u32; N];
+    let code = critical_section::with(|_| rom_read(addr, words.as_mut_ptr(), (N * 4) as u32));
+    检查(code)?;
+    Ok(words)
+}
+
+/// 擦掉`addr`所在的扇区，再把`words`写进去，扇区里的其它内容都会被清掉
+pub fn 写入扇区(addr: u32, words: &[u32]) -> Result<(), FlashErr> {
+    if words.len() * 4 > SECTOR_SIZE {
+        return Err(FlashErr::TooLong);
+    }
+    let code = critical_section::with(|_| {
+        rom_erase_and_write(addr, words.as_ptr(), (words.len() * 4) as u32)
+    });
+    检查(code)
+}
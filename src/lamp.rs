@@ -0,0 +1,137 @@
+use crate::command::Position;
+use crate::screen::{BOTTOM_Y, RIGHT_X};
+use alloc::{vec, vec::Vec};
+use core::cell::RefCell;
+use critical_section::Mutex;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::Point};
+
+/// 当前使用的灯组
+pub static LAMP_BANK: Mutex<RefCell<Option<LampBank>>> = Mutex::new(RefCell::new(None));
+
+/// 每个灯最后一次被设置的颜色，重绘屏幕时用来恢复
+pub static LAMP_STATE: Mutex<RefCell<Option<LampState>>> = Mutex::new(RefCell::new(None));
+
+/// 默认半径，和最早写死的直径20保持一致
+pub const DEFAULT_RADIUS: u32 = 10;
+
+/// 一组灯最多几个，堆内存只有32K
+pub const MAX_LAMPS: usize = 16;
+
+/// 最大半径，整个圆要能放进屏幕的高度
+pub const MAX_RADIUS: u32 = BOTTOM_Y as u32 / 2;
+
+/// 单个灯的圆心与半径
+#[derive(Debug, Clone)]
+pub struct Lamp {
+    pub center: Point,
+    pub radius: u32,
+}
+
+/// 灯的排布方式，总数不超过[`MAX_LAMPS`]，半径不超过[`MAX_RADIUS`]，
+/// (命令格式：lamps row,count[,radius] / lamps grid,cols,rows[,radius] / lamps at,x:y[:radius],...)
+#[derive(Debug, Clone)]
+pub enum Layout {
+    /// 在屏幕中线上一字排开
+    Row { count: usize, radius: u32 },
+    /// 铺满整个屏幕的网格，按行从左到右编号
+    Grid {
+        cols: usize,
+        rows: usize,
+        radius: u32,
+    },
+    /// 直接给出每个灯的坐标
+    Custom(Vec<Lamp>),
+}
+
+impl Default for Layout {
+    /// 上电时的三个灯：左、中、右
+    fn default() -> Self {
+        Layout::Row {
+            count: 3,
+            radius: DEFAULT_RADIUS,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LampBank {
+    lamps: Vec<Lamp>,
+}
+
+impl LampBank {
+    pub fn new(layout: &Layout) -> Self {
+        let (width, height) = (RIGHT_X as i32, BOTTOM_Y as i32);
+        let lamps = match layout {
+            Layout::Row { count, radius } => (0..*count)
+                .map(|i| Lamp {
+                    center: Point::new(width * (i as i32 + 1) / (*count as i32 + 1), height / 2),
+                    radius: *radius,
+                })
+                .collect(),
+            Layout::Grid { cols, rows, radius } => {
+                let (cell_w, cell_h) = (width / *cols as i32, height / *rows as i32);
+                (0..cols * rows)
+                    .map(|i| Lamp {
+                        center: Point::new(
+                            cell_w * (i % cols) as i32 + cell_w / 2,
+                            cell_h * (i / cols) as i32 + cell_h / 2,
+                        ),
+                        radius: *radius,
+                    })
+                    .collect()
+            }
+            Layout::Custom(lamps) => lamps.clone(),
+        };
+        LampBank { lamps }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lamps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lamps.is_empty()
+    }
+
+    pub fn lamps(&self) -> &[Lamp] {
+        &self.lamps
+    }
+
+    /// 把命令里的位置换算成灯的序号，left是第一个，right是最后一个，middle是正中间那个
+    pub fn index_of(&self, position: &Position) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let index = match position {
+            Position::Left => 0,
+            Position::Middle => self.lamps.len() / 2,
+            Position::Right => self.lamps.len() - 1,
+            Position::Index(index) => *index,
+        };
+        (index < self.lamps.len()).then_some(index)
+    }
+}
+
+/// 记录灯组里每个灯当前的颜色，`None`表示这个灯还没有被点亮过
+#[derive(Debug)]
+pub struct LampState {
+    colors: Vec<Option<Rgb565>>,
+}
+
+impl LampState {
+    pub fn new(len: usize) -> Self {
+        LampState {
+            colors: vec![None; len],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, color: Rgb565) {
+        if let Some(slot) = self.colors.get_mut(index) {
+            *slot = Some(color);
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Rgb565> {
+        self.colors.get(index).copied().flatten()
+    }
+}
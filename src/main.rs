@@ -1,24 +1,42 @@
 #![no_std]
 #![no_main]
 
+mod alarm;
+mod backup;
 mod command;
+mod flash;
+mod lamp;
+mod mode;
+mod node;
 mod screen;
+mod sync;
 mod time;
 
+use clock_core::{ack, calendar, frame, lora, relay, schedule};
+
 extern crate alloc;
 
-use alloc::{borrow::ToOwned, collections::VecDeque, format, string::ToString, vec::Vec};
-use command::Command;
-use core::mem::MaybeUninit;
+use alloc::{
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use command::{Command, CommandErr, Countdown, LoraSetting, Stopwatch};
+use core::{cell::Cell, mem::MaybeUninit};
+use critical_section::Mutex;
 use embedded_hal_bus::spi::ExclusiveDevice;
 use esp_backtrace as _;
 use esp_hal::{
     clock::ClockControl,
     delay::Delay,
+    efuse::Efuse,
     gpio::{self, IO},
     interrupt::{self, Priority},
-    peripherals::{Interrupt, Peripherals},
-    prelude::{nb::block, *},
+    peripherals::{Interrupt, Peripherals, UART1},
+    prelude::*,
+    rng::Rng,
+    rtc_cntl::Rtc,
     spi::master::Spi,
     systimer::SystemTimer,
     timer::{TimerGroup, TimerInterrupts},
@@ -26,8 +44,31 @@ use esp_hal::{
         config::{Config, DataBits, Parity, StopBits},
         ClockSource, TxRxPins, Uart,
     },
+    Blocking,
 };
 use esp_println::println;
+use mode::Elapsed;
+use schedule::Trigger;
+
+/// LoRa模块的驱动，M0->4 M1->5 AUX->8
+type Radio = lora::Lora<
+    gpio::GpioPin<gpio::Output<gpio::PushPull>, 4>,
+    gpio::GpioPin<gpio::Output<gpio::PushPull>, 5>,
+    gpio::GpioPin<gpio::Input<gpio::PullUp>, 8>,
+    Delay,
+>;
+
+/// 手上的模块是E32，换成E22时改这里
+const LORA_MODEL: lora::Model = lora::Model::E32;
+
+/// 一帧里两个字节之间最多隔多少毫秒，LoRa模块把长的帧拆成几个包发，包之间要等一个包在空中的时间
+const FRAME_TIMEOUT_MS: u64 = 500;
+
+/// 正在执行的命令来自哪一帧，这时的回复也打包成帧，带上同样的序号
+static REPLY_SEQ: Mutex<Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+/// 正在执行的命令经过了中继，回复时带上这个跳数
+static REPLY_TTL: Mutex<Cell<Option<u8>>> = Mutex::new(Cell::new(None));
 
 #[global_allocator]
 static ALLOCATOR: esp_alloc::EspHeap = esp_alloc::EspHeap::empty();
@@ -70,16 +111,21 @@ fn main() -> ! {
     critical_section::with(|cs| {
         time::TIMER0.borrow_ref_mut(cs).replace(timer0);
 
-        // 顺便初始化用于延时命令的两个栈
-        command::COMMAND_BUCKET
+        // 顺便初始化延时命令的调度器，以及到期等待执行的队列
+        command::SCHEDULER
             .borrow_ref_mut(cs)
-            .replace(VecDeque::new());
-        command::DELAY_BUCKET
-            .borrow_ref_mut(cs)
-            .replace(VecDeque::new());
+            .replace(schedule::Scheduler::new(SystemTimer::TICKS_PER_SECOND));
+        command::READY.borrow_ref_mut(cs).replace(VecDeque::new());
 
         // 初始化系统时间的闹钟
         time::ALARM0.borrow_ref_mut(cs).replace(alarm0);
+
+        // 上电时使用默认的三个灯，都还没点亮
+        let bank = lamp::LampBank::new(&lamp::Layout::default());
+        lamp::LAMP_STATE
+            .borrow_ref_mut(cs)
+            .replace(lamp::LampState::new(bank.len()));
+        lamp::LAMP_BANK.borrow_ref_mut(cs).replace(bank);
     });
 
     interrupt::enable(Interrupt::SYSTIMER_TARGET0, Priority::Priority1).unwrap();
@@ -89,11 +135,38 @@ fn main() -> ! {
     // 加载时间必须要在刷新屏幕之前，屏幕刷新太耗时了
     // 时间格式1996-12-19T16:39:57-08:00
     let now: &[u8] = include_bytes!("../assets/time.bin");
-    log::info!("解析时间： {:?}", now);
-    unsafe {
-        time::NOW.build(now);
-        log::info!("运行时获得时间： {}", time::NOW);
+    log::info!("解析时间： {:?}", core::str::from_utf8(now));
+    // 软复位之后RTC内存里的备份还在，比编译时间准，优先用它
+    let rtc = Rtc::new(peripherals.LPWR, None);
+    let backup = critical_section::with(|cs| {
+        backup::RTC.borrow_ref_mut(cs).replace(rtc);
+        backup::读取时钟(cs)
+    });
+    match backup {
+        Some(backup) => {
+            log::info!("从RTC内存恢复时间 {:?}", backup);
+            time::恢复时间(backup.utc_ms, backup.ppb, backup.zone);
+            critical_section::with(|cs| {
+                node::NODE.borrow(cs).set(backup.node);
+                alarm::ALARMS.borrow_ref_mut(cs).extend(backup.alarms);
+            });
+        }
+        // 编译机器的时区只作为默认时区，之后可以用tz命令修改
+        None => {
+            time::初始化时间(now);
+            time::恢复修正值();
+            // 掉过电的话闹钟从flash里读
+            let alarms = alarm::读取闹钟(&time::本地时间());
+            log::info!("从flash读出{}个闹钟", alarms.len());
+            critical_section::with(|cs| alarm::ALARMS.borrow_ref_mut(cs).extend(alarms));
+            // 默认地址取MAC地址的最后一个字节，同一批板子一般不会重复
+            node::设置地址(Efuse::read_base_mac_address()[5] as u16);
+        }
     }
+    log::info!("本节点地址 {}", node::地址());
+    log::info!("运行时获得时间： {}", time::本地时间());
+    let first_id = (Rng::new(peripherals.RNG).random() as u16).max(1);
+    critical_section::with(|cs| command::NEXT_SEND_ID.borrow(cs).set(first_id));
 
     // 初始化串口设备
     let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
@@ -116,8 +189,30 @@ fn main() -> ! {
         None,
     );
 
+    // 初始化LoRa模块。AUX接了上拉，没接模块时也是高电平，所以要读一次参数，模块回复对了才算接上了。
+    // 没有模块只是不能配置参数，串口照常使用
+    let mut radio = match Radio::new(
+        LORA_MODEL,
+        io.pins.gpio4.into_push_pull_output(),
+        io.pins.gpio5.into_push_pull_output(),
+        io.pins.gpio8.into_pull_up_input(),
+        delay,
+    )
+    .and_then(|mut radio| radio.read_config(&mut serial1).map(|_| radio))
+    {
+        Ok(radio) => Some(radio),
+        Err(e) => {
+            log::warn!("LoRa模块没有响应 {:?}", e);
+            None
+        }
+    };
+
     // 初始化屏幕
     // SCK->2 SDA->3 RES->10 DC->6 CS->7
+    // BOOT键，按下时是低电平，用来确认闹钟
+    let button = io.pins.gpio9.into_pull_up_input();
+    let mut button_down = false;
+
     let sck = io.pins.gpio2;
     let sda = io.pins.gpio3;
     let res = io.pins.gpio10.into_push_pull_output();
@@ -134,82 +229,81 @@ fn main() -> ! {
 
     // 其实这里不使用critical_section而是直接unsafe是因为懒得改了，实际不应该这样
     unsafe {
-        use screen::{屏幕初始化, 绘制数字, 绘制边框, ST7735};
+        use screen::{屏幕初始化, 绘制数字, 绘制日期, 绘制边框, ST7735};
         ST7735
             .as_mut_ptr()
             .write(st7735_lcd::ST7735::new(spi, dc, res, false, true, 110, 161));
         屏幕初始化(&mut *ST7735.as_mut_ptr(), &mut delay);
         绘制边框(&mut *ST7735.as_mut_ptr());
         绘制数字(&mut *ST7735.as_mut_ptr());
+        绘制日期(&mut *ST7735.as_mut_ptr());
     }
 
     println!("drew down");
 
     println!("Start");
     let mut buf = Vec::new();
+    let mut decoder = frame::Decoder::new(FRAME_TIMEOUT_MS * SystemTimer::TICKS_PER_SECOND / 1000);
     loop {
+        // 先执行已经到期的定时命令，它们由闹钟中断放进READY队列
+        while let Some(command) = critical_section::with(|cs| {
+            command::READY
+                .borrow_ref_mut(cs)
+                .as_mut()
+                .unwrap()
+                .pop_front()
+        }) {
+            println!("执行定时命令 {:?}", command);
+            // 出错时各个命令已经回复过自己的错误，这里只记日志
+            if let Err(e) = 执行命令(command, &mut serial1, &mut radio, &mut delay) {
+                println!("定时命令出错 {:?}", e);
+            }
+        }
+
+        // 正在响的闹钟在这里画，时钟中断只负责切换亮灭
+        alarm::刷新闪烁();
+
+        // 按下BOOT键确认闹钟，只在按下的那一刻算一次
+        if button.is_low() != button_down {
+            button_down = !button_down;
+            if button_down && alarm::确认闹钟() {
+                println!("闹钟已通过按键确认");
+            }
+        }
+
+        // 用send发出去的命令没等到回复时重发
+        检查重发(&mut serial1);
+
+        // 作为master时定期广播自己的时间
+        if sync::该广播了(SystemTimer::now()) {
+            回复(&mut serial1, &format!("tsync {}\n", time::当前毫秒()));
+        }
+
         // 这里遇到了一些问题，hal库中有read_byte()和drain_fifo()两个方法从串口读取数据，前者一个字符一个字符读，后者一次性读取所有数据，而后者无法正常使用，所以还是使用比较原始的方法读取
-        match block!(serial1.read_byte()) {
-            Ok(byte) => match byte {
-                b'\n' => {
-                    let command = Command::try_from(&buf);
-                    match &command {
-                        Ok(Command::Ping) => {
-                            serial1.write_bytes(b"pong").unwrap();
-                            println!("pong");
-                        }
-                        Ok(Command::Reload) => unsafe {
-                            use screen::{屏幕初始化, 绘制边框, ST7735};
-                            屏幕初始化(&mut *ST7735.as_mut_ptr(), &mut delay);
-                            绘制边框(&mut *ST7735.as_mut_ptr());
-                        },
-                        Ok(Command::Blink(color, position)) => {
-                            println!("Blink {:?} {:?}", color, position);
-                            screen::改变灯的颜色(&command.unwrap());
-                        }
-                        Ok(Command::DelayBlink(color, position, delay)) => {
-                            println!("DelayBlink {:?} {:?} {:?}", color, position, delay);
-                            critical_section::with(|cs| {
-                                command::COMMAND_BUCKET
-                                    .borrow_ref_mut(cs)
-                                    .as_mut()
-                                    .unwrap()
-                                    .push_back(Command::Blink(
-                                        color.to_owned(),
-                                        position.to_owned(),
-                                    ));
-
-                                let mut delay_bucket = command::DELAY_BUCKET.borrow_ref_mut(cs);
-                                let delay_bucket = delay_bucket.as_mut().unwrap();
-                                delay_bucket.push_back(delay.clone());
-
-                                let mut alarm0 = time::ALARM0.borrow_ref_mut(cs);
-                                let alarm0 = alarm0.as_mut().unwrap();
-
-                                if delay_bucket.len() == 1 {
-                                    println!("计时器启动");
-                                    // alarm0.set_period(
-                                    //     (delay_bucket.front().unwrap().clone() as u32).secs(),
-                                    // );
-                                    alarm0.set_target(
-                                        SystemTimer::now()
-                                            + (SystemTimer::TICKS_PER_SECOND
-                                                * (delay_bucket.front().unwrap().clone() as u64)),
-                                    );
-                                    alarm0.enable_interrupt(true);
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            serial1
-                                .write_bytes(
-                                    &format!("Unknown command {:?}", e).to_string().into_bytes(),
-                                )
-                                .unwrap();
-                            screen::出问题了(&format!("Unknown command {:?}", e).to_string());
-                            println!("Unknown command {:?}", e);
+        // 不能再用block!一直等串口了，否则到期的定时命令要等下一个字节来了才会执行
+        match serial1.read_byte() {
+            // 行首的同步字节说明后面是一帧，收完整帧之前的字节都交给解码器
+            Ok(byte)
+                if decoder.is_busy(SystemTimer::now())
+                    || (byte == frame::SYNC && buf.is_empty()) =>
+            {
+                let mut next = decoder.push(byte, SystemTimer::now());
+                while let Some(frame) = next {
+                    match core::str::from_utf8(&frame.payload) {
+                        Ok(text) => {
+                            critical_section::with(|cs| REPLY_SEQ.borrow(cs).set(Some(frame.seq)));
+                            处理一行(text, &mut serial1, &mut radio, &mut delay);
+                            critical_section::with(|cs| REPLY_SEQ.borrow(cs).set(None));
                         }
+                        Err(_) => println!("帧{}的内容不是文本", frame.seq),
                     }
+                    next = decoder.next_frame();
+                }
+            }
+            Ok(byte) => match byte {
+                b'\n' => {
+                    let text: String = buf.iter().collect();
+                    处理一行(&text, &mut serial1, &mut radio, &mut delay);
                     buf.clear();
                 }
                 b'\r' => {
@@ -223,3 +317,667 @@ fn main() -> ! {
         }
     }
 }
+
+/// 处理收到的一行命令，文本命令和帧里的命令都在这里解析执行。
+/// 其它节点的回复里只处理ACK、NAK和对时的消息，不是发给本节点的命令直接丢掉，免得节点之间互相回复个没完
+fn 处理一行(
+    text: &str,
+    serial1: &mut Uart<'_, UART1, Blocking>,
+    radio: &mut Option<Radio>,
+    delay: &mut Delay,
+) {
+    let (target, line) = node::拆分目标(text);
+    let hop = relay::Hop::parse(text);
+    if let Some(hop) = &hop {
+        // 同一条消息从几条路径到达时只处理第一次
+        let now = SystemTimer::now();
+        if !critical_section::with(|cs| {
+            command::RECENT
+                .borrow_ref_mut(cs)
+                .first_seen(hop.key(), now)
+        }) {
+            return;
+        }
+        // 只发给本节点的命令不用再转
+        if node::是中继() && target != Some(node::Target::Node(node::地址())) {
+            if let Some(forward) = hop.forward() {
+                println!("转发 {}", forward.trim_end());
+                发送(serial1, &format!("{}\n", forward.trim_end()));
+            }
+        }
+    }
+    if let Some((from, body)) = node::拆分回复(text) {
+        // 对时的几条消息也是带着地址发出来的，要照常执行
+        match Command::try_from(body.trim_end()) {
+            Ok(Command::TimeOffset(offset)) => println!("follower {} offset {}ms", from, offset),
+            Ok(
+                command @ (Command::TimeBroadcast(_)
+                | Command::TimeRequest(_)
+                | Command::TimeReply(..)),
+            ) => {
+                let _ = 执行命令(command, serial1, radio, delay);
+            }
+            _ => 收到回复(serial1, from, body),
+        }
+        return;
+    }
+    if !node::是发给我的(target) {
+        return;
+    }
+    // 经过中继来的命令，回复也要带上跳数才能原路回去
+    let ttl = hop.map(|_| relay::REPLY_TTL);
+    critical_section::with(|cs| REPLY_TTL.borrow(cs).set(ttl));
+    执行一行(line, serial1, radio, delay);
+    critical_section::with(|cs| REPLY_TTL.borrow(cs).set(None));
+}
+
+/// 执行去掉目标前缀的一行，带序号的命令回复ACK或NAK
+fn 执行一行(
+    line: &str,
+    serial1: &mut Uart<'_, UART1, Blocking>,
+    radio: &mut Option<Radio>,
+    delay: &mut Delay,
+) {
+    let (id, line) = ack::split_id(line);
+    // 重发过来的命令不再执行，只把上次的回复再发一遍
+    if let Some(id) = id {
+        let now = SystemTimer::now();
+        if let Some(reply) = critical_section::with(|cs| command::SEEN.borrow_ref(cs).get(id, now))
+        {
+            回复(serial1, &format!("{}\n", ack::Answer(reply, id.attempt)));
+            return;
+        }
+    }
+    // 不带序号的命令解析出错时照旧回复文字，带序号的回复NAK
+    let result = match Command::try_from(line) {
+        Ok(command) => 执行命令(command, serial1, radio, delay),
+        Err(e) => {
+            if id.is_none() {
+                回复(serial1, &format!("Unknown command {:?}\n", e));
+            }
+            screen::出问题了(&format!("Unknown command {:?}", e).to_string());
+            println!("Unknown command {:?}", e);
+            Err(e)
+        }
+    };
+    if let Some(id) = id {
+        let reply = match result {
+            Ok(()) => ack::Reply::Ack(id.seq),
+            Err(e) => ack::Reply::Nak(id.seq, e.code()),
+        };
+        critical_section::with(|cs| {
+            command::SEEN
+                .borrow_ref_mut(cs)
+                .insert(id.from, reply, SystemTimer::now())
+        });
+        回复(serial1, &format!("{}\n", ack::Answer(reply, id.attempt)));
+    }
+}
+
+/// 其它节点的回复里只关心对send命令的ACK和NAK，只有命令发给的那个节点回复的才算，收到后不再重发
+fn 收到回复(serial1: &mut Uart<'_, UART1, Blocking>, from: u16, body: &str) {
+    let Some(reply) = ack::Reply::parse(body) else {
+        return;
+    };
+    let sent = critical_section::with(|cs| {
+        let mut outbox = command::OUTBOX.borrow_ref_mut(cs);
+        let index = outbox
+            .iter()
+            .position(|(retry, to, _)| retry.id == reply.id() && *to == from)?;
+        Some(outbox.remove(index))
+    });
+    if sent.is_none() {
+        return;
+    }
+    let result = match reply {
+        ack::Reply::Ack(id) => format!("send {} ok\n", id),
+        ack::Reply::Nak(id, code) => format!("send {} nak {}\n", id, code),
+    };
+    println!("{}", result.trim_end());
+    回复(serial1, &result);
+}
+
+/// 在目标前缀后面插入本节点的地址、序号和第几次
+fn 带上序号(line: &str, seq: u16, attempt: u8) -> String {
+    let (_, command) = node::拆分目标(line);
+    let prefix = &line[..line.len() - command.len()];
+    let id = ack::Id {
+        from: Some(node::地址()),
+        seq,
+        attempt,
+    };
+    format!("{}{} {}\n", prefix, id, command)
+}
+
+/// 给命令加上序号发出去，`to`回复ACK之前由主循环重发
+fn 发送命令(serial1: &mut Uart<'_, UART1, Blocking>, to: u16, line: &str) {
+    let now = SystemTimer::now();
+    let id = critical_section::with(|cs| {
+        let next = command::NEXT_SEND_ID.borrow(cs);
+        let id = next.get();
+        next.set(id.wrapping_add(1).max(1));
+        id
+    });
+    let message = 带上序号(line, id, 1);
+    记下自己发的(&message);
+    serial1.write_bytes(message.as_bytes()).unwrap();
+    critical_section::with(|cs| {
+        command::OUTBOX.borrow_ref_mut(cs).push((
+            ack::Retry::new(
+                id,
+                now,
+                command::SEND_TIMEOUT_SECS * SystemTimer::TICKS_PER_SECOND,
+                command::MAX_SENDS,
+            ),
+            to,
+            line.into(),
+        ))
+    });
+    回复(serial1, &format!("send {}\n", id));
+}
+
+/// 等不到回复的命令按退避时间重发，发够次数还没有回复就放弃
+fn 检查重发(serial1: &mut Uart<'_, UART1, Blocking>) {
+    let now = SystemTimer::now();
+    let mut resend = Vec::new();
+    let mut failed = Vec::new();
+    critical_section::with(|cs| {
+        command::OUTBOX
+            .borrow_ref_mut(cs)
+            .retain_mut(|(retry, _, line)| match retry.poll(now) {
+                ack::Action::Wait => true,
+                ack::Action::Resend => {
+                    println!("重发 {} 第{}次", retry.id, retry.sends());
+                    resend.push(带上序号(line, retry.id, retry.sends()));
+                    true
+                }
+                ack::Action::GiveUp => {
+                    failed.push(retry.id);
+                    false
+                }
+            })
+    });
+    for message in resend {
+        记下自己发的(&message);
+        serial1.write_bytes(message.as_bytes()).unwrap();
+    }
+    for id in failed {
+        println!("send {} failed", id);
+        回复(serial1, &format!("send {} failed\n", id));
+    }
+}
+
+/// 先读出模块现在的参数，只改一项再写回去，掉电也保存
+fn 修改模块参数(
+    radio: &mut Radio,
+    serial1: &mut Uart<'_, UART1, Blocking>,
+    change: impl FnOnce(&mut lora::Config),
+) -> Result<lora::Config, lora::LoraErr> {
+    let mut config = radio.read_config(serial1)?;
+    change(&mut config);
+    radio.write_config(serial1, &config, true)
+}
+
+/// 回复前面带上本节点的地址，其它节点看到就知道这不是发给它们的命令
+fn 回复(serial1: &mut Uart<'_, UART1, Blocking>, text: &str) {
+    let ttl = critical_section::with(|cs| REPLY_TTL.borrow(cs).get());
+    let text = match ttl {
+        Some(ttl) => format!("{}~{}>{}", node::地址(), ttl, text),
+        None => format!("{}>{}", node::地址(), text),
+    };
+    发送(serial1, &text);
+}
+
+/// 命令是从帧里来的话，回复和转发也打包成帧，带上同样的序号
+fn 发送(serial1: &mut Uart<'_, UART1, Blocking>, text: &str) {
+    记下自己发的(text);
+    let seq = critical_section::with(|cs| REPLY_SEQ.borrow(cs).get());
+    // 回复太长装不进一帧时还是按文本发
+    match seq.map(|seq| frame::encode(seq, text.as_bytes())) {
+        Some(Ok(bytes)) => serial1.write_bytes(&bytes).unwrap(),
+        _ => serial1.write_bytes(text.as_bytes()).unwrap(),
+    };
+}
+
+/// 自己发出或者转发的带跳数的消息记进RECENT，邻居转回来时当作见过的丢掉
+fn 记下自己发的(text: &str) {
+    if let Some(hop) = relay::Hop::parse(text) {
+        let now = SystemTimer::now();
+        critical_section::with(|cs| {
+            command::RECENT
+                .borrow_ref_mut(cs)
+                .first_seen(hop.key(), now)
+        });
+    }
+}
+
+/// 回报本节点的地址、所在的组和是否在当中继
+fn 节点状态(serial1: &mut Uart<'_, UART1, Blocking>) {
+    let node = critical_section::with(|cs| node::NODE.borrow(cs).get());
+    let groups: Vec<u8> = (0..=node::MAX_GROUP)
+        .filter(|group| node.groups & 1 << group != 0)
+        .collect();
+    let status = format!(
+        "addr {} groups {:?} relay {}",
+        node.address,
+        groups,
+        if node.relay { "on" } else { "off" }
+    );
+    println!("{}", status);
+    回复(serial1, &format!("{}\n", status));
+}
+
+/// 执行一条命令，串口收到的命令和到期的定时命令都在这里执行
+fn 执行命令(
+    command: Command,
+    serial1: &mut Uart<'_, UART1, Blocking>,
+    radio: &mut Option<Radio>,
+    delay: &mut Delay,
+) -> Result<(), CommandErr> {
+    match command {
+        Command::Ping => {
+            回复(serial1, &format!("pong {}\n", node::地址()));
+            println!("pong");
+        }
+        Command::Reload => {
+            screen::重绘屏幕(delay);
+        }
+        Command::Blink(color, position) => {
+            println!("Blink {:?} {:?}", color, position);
+            if let Err(e) = screen::改变灯的颜色(color, &position) {
+                println!("Blink failed {:?}", e);
+                回复(serial1, &format!("{:?}\n", e));
+                return Err(e);
+            }
+        }
+        Command::Lamps(layout) => {
+            println!("Lamps {:?}", layout);
+            screen::重新排布灯(&layout);
+        }
+        Command::Message(text) => {
+            println!("Message {}", text);
+            screen::显示消息(&text);
+        }
+        Command::After(secs, later) => {
+            println!("After {} {:?}", secs, later);
+            let id = critical_section::with(|cs| {
+                let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+                let scheduler = scheduler.as_mut().unwrap();
+                let id = scheduler.push(
+                    SystemTimer::now() + SystemTimer::TICKS_PER_SECOND * secs as u64,
+                    *later,
+                );
+                time::设置闹钟(
+                    time::ALARM0.borrow_ref_mut(cs).as_mut().unwrap(),
+                    scheduler.next_deadline(),
+                );
+                id
+            });
+            回复(serial1, &format!("queued {}\n", id));
+        }
+        Command::At(at, later) => {
+            println!("At {} {:?}", at, later);
+            let id = critical_section::with(|cs| {
+                let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+                let scheduler = scheduler.as_mut().unwrap();
+                let id = scheduler.push_trigger(
+                    SystemTimer::now(),
+                    time::本地时间().secs_of_day(),
+                    Trigger::At(at),
+                    None,
+                    *later,
+                );
+                time::设置闹钟(
+                    time::ALARM0.borrow_ref_mut(cs).as_mut().unwrap(),
+                    scheduler.next_deadline(),
+                );
+                id
+            });
+            回复(serial1, &format!("queued {}\n", id));
+        }
+        Command::Every(trigger, repeat, later) => {
+            println!("Every {}{:?} {:?}", trigger, repeat, later);
+            let id = critical_section::with(|cs| {
+                let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+                let scheduler = scheduler.as_mut().unwrap();
+                let id = scheduler.push_trigger(
+                    SystemTimer::now(),
+                    time::本地时间().secs_of_day(),
+                    trigger,
+                    repeat,
+                    *later,
+                );
+                time::设置闹钟(
+                    time::ALARM0.borrow_ref_mut(cs).as_mut().unwrap(),
+                    scheduler.next_deadline(),
+                );
+                id
+            });
+            回复(serial1, &format!("queued {}\n", id));
+        }
+        Command::SetTime(date, ms_of_day, offset) => {
+            let calibrated = time::设置时间(date, ms_of_day, offset);
+            println!("SetTime {}", time::本地时间());
+            回复(serial1, &format!("time {}\n", time::本地时间()));
+            match calibrated {
+                Some(Ok(ppb)) => {
+                    let saved = time::保存修正值(ppb);
+                    let reply = match &saved {
+                        Ok(()) => format!("cal done {}\n", time::Drift(ppb)),
+                        Err(e) => format!("cal done {}, not saved {:?}\n", time::Drift(ppb), e),
+                    };
+                    回复(serial1, &reply);
+                    saved.map_err(|_| CommandErr::NotSaved)?;
+                }
+                Some(Err(e)) => {
+                    println!("Calibrate failed {:?}", e);
+                    回复(serial1, &format!("cal {}\n", e));
+                    return Err(CommandErr::Calibration);
+                }
+                None => {}
+            }
+        }
+        Command::Sync(role) => {
+            if let Some(role) = role {
+                sync::设置角色(role);
+                // 成为follower时不等下一次广播，马上对一次时
+                if let Some(sent) = sync::发出请求() {
+                    回复(serial1, &format!("treq {}\n", sent));
+                }
+            }
+            let (role, offset) = critical_section::with(|cs| {
+                (
+                    sync::ROLE.borrow(cs).get(),
+                    sync::LAST_OFFSET.borrow(cs).get(),
+                )
+            });
+            println!("Sync {} {:?}", role, offset);
+            let reply = match offset {
+                Some(offset) => format!("sync {} offset {}ms\n", role, offset),
+                None => format!("sync {}\n", role),
+            };
+            回复(serial1, &reply);
+        }
+        Command::TimeBroadcast(master) => {
+            // 广播只用来提醒follower来对时，带的时间没有补偿延迟，不直接用
+            println!("TimeBroadcast {}ms, local {}ms", master, time::当前毫秒());
+            if let Some(sent) = sync::发出请求() {
+                回复(serial1, &format!("treq {}\n", sent));
+            }
+        }
+        Command::TimeRequest(sent) => {
+            let role = critical_section::with(|cs| sync::ROLE.borrow(cs).get());
+            if let sync::Role::Master(_) = role {
+                回复(serial1, &format!("tresp {} {}\n", sent, time::当前毫秒()));
+            }
+        }
+        Command::TimeReply(sent, master) => {
+            if let Some(utc_ms) = sync::收到回复(sent, master) {
+                let offset = time::同步时间(utc_ms);
+                critical_section::with(|cs| sync::LAST_OFFSET.borrow(cs).set(Some(offset)));
+                println!("TimeReply offset {}ms", offset);
+                回复(serial1, &format!("toff {}\n", offset));
+            }
+        }
+        Command::TimeOffset(offset) => {
+            println!("follower offset {}ms", offset);
+        }
+        Command::Countdown(action) => {
+            let reply = match action {
+                Countdown::Start(secs, lamp) => {
+                    mode::开始倒计时(secs, lamp);
+                    Some(format!("countdown {}s\n", secs))
+                }
+                Countdown::Pause => {
+                    mode::暂停倒计时().map(|remaining| format!("countdown paused {}s\n", remaining))
+                }
+                Countdown::Resume => {
+                    mode::继续倒计时().map(|remaining| format!("countdown {}s\n", remaining))
+                }
+                Countdown::Cancel => {
+                    mode::取消倒计时().then(|| "countdown cancelled\n".to_string())
+                }
+            };
+            time::重画时间();
+            let Some(reply) = reply else {
+                回复(serial1, "no countdown\n");
+                return Err(CommandErr::NotFound);
+            };
+            println!("Countdown {}", reply.trim_end());
+            回复(serial1, &reply);
+        }
+        Command::Stopwatch(action) => {
+            let reply = match action {
+                Stopwatch::Start => Some(format!("stopwatch {}\n", Elapsed(mode::开始秒表()))),
+                Stopwatch::Stop => mode::停止秒表()
+                    .map(|elapsed| format!("stopwatch stopped {}\n", Elapsed(elapsed))),
+                Stopwatch::Lap => mode::秒表记圈().map(|(laps, lap, total)| {
+                    format!("lap {} {} total {}\n", laps, Elapsed(lap), Elapsed(total))
+                }),
+                Stopwatch::Reset => mode::重置秒表().then(|| "stopwatch reset\n".to_string()),
+            };
+            time::重画时间();
+            let Some(reply) = reply else {
+                回复(serial1, "no stopwatch\n");
+                return Err(CommandErr::NotFound);
+            };
+            println!("Stopwatch {}", reply.trim_end());
+            回复(serial1, &reply);
+        }
+        Command::Alarm(secs_of_day, color, lamp) => {
+            let Some(index) = alarm::添加闹钟(&time::本地时间(), secs_of_day, color, lamp)
+            else {
+                回复(serial1, &format!("alarm full, max {}\n", alarm::MAX_ALARMS));
+                return Err(CommandErr::Full);
+            };
+            let saved = alarm::保存闹钟();
+            let reply = match &saved {
+                Ok(()) => format!("alarm {} set\n", index),
+                Err(e) => format!("alarm {} set, not saved {:?}\n", index, e),
+            };
+            println!("Alarm {}", reply.trim_end());
+            回复(serial1, &reply);
+            saved.map_err(|_| CommandErr::NotSaved)?;
+        }
+        Command::Alarms => {
+            let alarms = critical_section::with(|cs| alarm::ALARMS.borrow_ref(cs).clone());
+            if alarms.is_empty() {
+                回复(serial1, "no alarms\n");
+            }
+            for (index, alarm) in alarms.iter().enumerate() {
+                let secs = alarm.secs_of_day;
+                let line = format!(
+                    "{} {:02}:{:02}:{:02} {:?} {:?}\n",
+                    index,
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60,
+                    alarm.color,
+                    alarm.lamp
+                );
+                回复(serial1, &line);
+            }
+        }
+        Command::RemoveAlarm(index) => {
+            if alarm::删除闹钟(index).is_none() {
+                回复(serial1, "no alarm\n");
+                return Err(CommandErr::NotFound);
+            }
+            let saved = alarm::保存闹钟();
+            let reply = match &saved {
+                Ok(()) => "alarm removed\n".into(),
+                Err(e) => format!("alarm removed, not saved {:?}\n", e),
+            };
+            回复(serial1, &reply);
+            saved.map_err(|_| CommandErr::NotSaved)?;
+        }
+        Command::Ack => {
+            if !alarm::确认闹钟() {
+                回复(serial1, "no alarm ringing\n");
+                return Err(CommandErr::NotFound);
+            }
+            回复(serial1, "ack\n");
+        }
+        Command::Clock(option) => {
+            let format = match option {
+                Some(option) => {
+                    let format = screen::设置时钟格式(option);
+                    time::重画时间();
+                    format
+                }
+                None => screen::时钟格式(),
+            };
+            println!("Clock {}", format);
+            回复(serial1, &format!("clock {}\n", format));
+        }
+        Command::Lora(setting) => {
+            let Some(radio) = radio.as_mut() else {
+                回复(serial1, "no lora module\n");
+                return Err(CommandErr::Lora);
+            };
+            let result = match setting {
+                None => radio.read_config(serial1),
+                Some(LoraSetting::Model(model)) => radio
+                    .set_model(model)
+                    .and_then(|_| radio.read_config(serial1)),
+                Some(LoraSetting::Address(address)) => {
+                    修改模块参数(radio, serial1, |config| config.address = address)
+                }
+                Some(LoraSetting::Channel(channel)) => {
+                    修改模块参数(radio, serial1, |config| config.channel = channel)
+                }
+                Some(LoraSetting::AirRate(rate)) => {
+                    修改模块参数(radio, serial1, |config| config.air_rate = rate)
+                }
+                Some(LoraSetting::Power(power)) => {
+                    修改模块参数(radio, serial1, |config| config.power = power)
+                }
+                Some(LoraSetting::Transparent) => {
+                    修改模块参数(radio, serial1, |config| config.fixed = false)
+                }
+            };
+            let reply = match &result {
+                Ok(config) => format!("lora {:?} {}\n", radio.model(), config),
+                Err(e) => format!("lora error {:?}\n", e),
+            };
+            println!("Lora {}", reply.trim_end());
+            回复(serial1, &reply);
+            result.map_err(|_| CommandErr::Lora)?;
+        }
+        Command::Calibrate(action) => {
+            let saved = match action {
+                Some(true) => {
+                    time::开始校准();
+                    Ok(())
+                }
+                Some(false) => {
+                    time::清除校准();
+                    time::保存修正值(0)
+                }
+                None => Ok(()),
+            };
+            let (ppb, calibration) = time::校准状态();
+            let status = match calibration {
+                time::Calibration::Idle => "",
+                time::Calibration::Waiting => " waiting for 1st time",
+                time::Calibration::First { .. } => " waiting for 2nd time",
+            };
+            println!("Calibrate {}{}", time::Drift(ppb), status);
+            match &saved {
+                Ok(()) => 回复(serial1, &format!("cal {}{}\n", time::Drift(ppb), status)),
+                Err(e) => 回复(
+                    serial1,
+                    &format!("cal {}{}, not saved {:?}\n", time::Drift(ppb), status, e),
+                ),
+            }
+            saved.map_err(|_| CommandErr::NotSaved)?;
+        }
+        Command::TimeZone(zone) => {
+            if let Some(zone) = zone {
+                time::设置时区(zone);
+            }
+            let zone = critical_section::with(|cs| time::ZONE.borrow(cs).get());
+            println!("TimeZone {}", zone);
+            回复(serial1, &format!("tz {} now {}\n", zone, time::本地时间()));
+        }
+        Command::Address(address) => {
+            if let Some(address) = address {
+                node::设置地址(address);
+            }
+            节点状态(serial1);
+        }
+        Command::Relay(relay) => {
+            if let Some(relay) = relay {
+                node::设置中继(relay);
+            }
+            节点状态(serial1);
+        }
+        Command::Group(group, join) => {
+            node::设置组(group, join);
+            节点状态(serial1);
+        }
+        Command::Send(to, line) => 发送命令(serial1, to, &line),
+        Command::Jobs => {
+            let now = SystemTimer::now();
+            let jobs = critical_section::with(|cs| {
+                command::SCHEDULER
+                    .borrow_ref(cs)
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{} {}s {}{}{:?}\n",
+                            entry.id,
+                            entry.deadline.saturating_sub(now) / SystemTimer::TICKS_PER_SECOND,
+                            entry.trigger,
+                            entry
+                                .remaining
+                                .map(|remaining| format!("x{} ", remaining))
+                                .unwrap_or_default(),
+                            entry.item
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            });
+            if jobs.is_empty() {
+                回复(serial1, "no jobs\n");
+            }
+            for job in jobs.iter() {
+                回复(serial1, job);
+            }
+        }
+        Command::Cancel(id) => {
+            let cancelled = critical_section::with(|cs| {
+                let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+                let scheduler = scheduler.as_mut().unwrap();
+                let cancelled = scheduler.cancel(id);
+                time::设置闹钟(
+                    time::ALARM0.borrow_ref_mut(cs).as_mut().unwrap(),
+                    scheduler.next_deadline(),
+                );
+                cancelled
+            });
+            match cancelled {
+                Some(command) => {
+                    println!("Cancel {} {:?}", id, command);
+                    回复(serial1, &format!("cancelled {}\n", id));
+                }
+                None => {
+                    回复(serial1, &format!("no job {}\n", id));
+                    return Err(CommandErr::NotFound);
+                }
+            }
+        }
+        Command::Clear => {
+            let count = critical_section::with(|cs| {
+                let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+                let count = scheduler.as_mut().unwrap().clear();
+                time::设置闹钟(time::ALARM0.borrow_ref_mut(cs).as_mut().unwrap(), None);
+                count
+            });
+            回复(serial1, &format!("cleared {}\n", count));
+        }
+    }
+    Ok(())
+}
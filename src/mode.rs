@@ -0,0 +1,295 @@
+//! 数字区显示的内容，平时是时钟，也可以切换成倒计时或者秒表
+
+use crate::calendar::DateTime;
+use crate::command::{Command, Position};
+use crate::{screen, time};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+};
+use critical_section::{CriticalSection, Mutex};
+use embedded_graphics::pixelcolor::Rgb565;
+use esp_hal::systimer::SystemTimer;
+
+/// 数字区现在的模式
+pub static MODE: Mutex<RefCell<Mode>> = Mutex::new(RefCell::new(Mode::Clock));
+
+/// 秒表上次算出的十分之一秒，画在秒后面
+static TENTHS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// 倒计时结束自己回到时钟时置上，数字、分隔符和AM/PM都要整个重画
+static REDRAW: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+#[derive(Debug, Clone)]
+pub enum Mode {
+    /// 显示当地时间
+    Clock,
+    /// 倒计时，到零时点亮`lamp`（如果有），显示一秒00:00后回到时钟
+    Countdown {
+        /// 倒计时结束时SystemTimer的计数
+        deadline: u64,
+        /// 暂停时还剩下的秒数
+        paused: Option<u32>,
+        lamp: Option<(Rgb565, Position)>,
+    },
+    /// 秒表，数字按时:分:秒显示，十分之一秒画在秒后面，超过一百小时后小时从0重新开始
+    Stopwatch {
+        /// 开始计时时SystemTimer的计数，停下再继续时往后挪，扣掉停下的那段
+        start: u64,
+        /// 停下时的计数
+        stopped: Option<u64>,
+        /// 上一次记圈时已经走过的计数
+        last_lap: u64,
+        laps: u32,
+    },
+}
+
+/// 倒计时最长多少秒，数字区只有两位小时
+pub const MAX_COUNTDOWN_SECS: u32 = 99 * 3600 + 59 * 60 + 59;
+
+/// 秒表每十分之一秒刷新一次
+const TENTH: u64 = SystemTimer::TICKS_PER_SECOND / 10;
+
+/// 秒表的时间，按`H:MM:SS.t`显示
+pub struct Elapsed(pub u64);
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let tenths = self.0 / TENTH;
+        let secs = tenths / 10;
+        write!(
+            f,
+            "{}:{:02}:{:02}.{}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            tenths % 10
+        )
+    }
+}
+
+/// 倒计时的结束时刻对齐到时钟的整秒上，这样数字和时钟在同一个中断里跳
+fn 截止计数(secs: u32) -> u64 {
+    let now = SystemTimer::now();
+    let reference = critical_section::with(|cs| time::NOW.borrow(cs).get());
+    now + reference.until_next_second(now) + secs as u64 * SystemTimer::TICKS_PER_SECOND
+}
+
+/// 开始倒计时，已经在倒计时的话重新开始
+pub fn 开始倒计时(secs: u32, lamp: Option<(Rgb565, Position)>) {
+    let deadline = 截止计数(secs);
+    critical_section::with(|cs| {
+        MODE.borrow(cs).replace(Mode::Countdown {
+            deadline,
+            paused: None,
+            lamp,
+        });
+    });
+}
+
+/// 暂停倒计时，返回剩下的秒数，没有在倒计时时返回None
+pub fn 暂停倒计时() -> Option<u32> {
+    critical_section::with(|cs| match &mut *MODE.borrow_ref_mut(cs) {
+        Mode::Countdown {
+            deadline, paused, ..
+        } => {
+            let remaining = *paused.get_or_insert_with(|| 剩余秒数(*deadline));
+            Some(remaining)
+        }
+        _ => None,
+    })
+}
+
+/// 继续暂停的倒计时，返回剩下的秒数，没有在倒计时时返回None
+pub fn 继续倒计时() -> Option<u32> {
+    critical_section::with(|cs| match &mut *MODE.borrow_ref_mut(cs) {
+        Mode::Countdown {
+            deadline, paused, ..
+        } => {
+            if let Some(remaining) = paused.take() {
+                *deadline = 截止计数(remaining);
+            }
+            Some(剩余秒数(*deadline))
+        }
+        _ => None,
+    })
+}
+
+/// 取消倒计时回到时钟，返回是否真的取消了
+pub fn 取消倒计时() -> bool {
+    critical_section::with(|cs| {
+        matches!(MODE.borrow(cs).replace(Mode::Clock), Mode::Countdown { .. })
+    })
+}
+
+/// 开始或者继续秒表，返回已经走过的计数
+pub fn 开始秒表() -> u64 {
+    let now = SystemTimer::now();
+    critical_section::with(|cs| {
+        let mut mode = MODE.borrow_ref_mut(cs);
+        match &mut *mode {
+            Mode::Stopwatch { start, stopped, .. } => {
+                if let Some(stopped) = stopped.take() {
+                    *start += now - stopped;
+                }
+                now - *start
+            }
+            _ => {
+                *mode = Mode::Stopwatch {
+                    start: now,
+                    stopped: None,
+                    last_lap: 0,
+                    laps: 0,
+                };
+                0
+            }
+        }
+    })
+}
+
+/// 停下秒表，返回走过的计数，没有在用秒表时返回None
+pub fn 停止秒表() -> Option<u64> {
+    let now = SystemTimer::now();
+    critical_section::with(|cs| match &mut *MODE.borrow_ref_mut(cs) {
+        Mode::Stopwatch { start, stopped, .. } => Some(*stopped.get_or_insert(now) - *start),
+        _ => None,
+    })
+}
+
+/// 记一圈，返回圈数、这一圈的计数和总共的计数，没有在用秒表时返回None
+pub fn 秒表记圈() -> Option<(u32, u64, u64)> {
+    let now = SystemTimer::now();
+    critical_section::with(|cs| match &mut *MODE.borrow_ref_mut(cs) {
+        Mode::Stopwatch {
+            start,
+            stopped,
+            last_lap,
+            laps,
+        } => {
+            let total = stopped.unwrap_or(now) - *start;
+            let lap = total - *last_lap;
+            *last_lap = total;
+            *laps += 1;
+            Some((*laps, lap, total))
+        }
+        _ => None,
+    })
+}
+
+/// 秒表清零并回到时钟，返回是否真的在用秒表
+pub fn 重置秒表() -> bool {
+    critical_section::with(|cs| {
+        let mut mode = MODE.borrow_ref_mut(cs);
+        let reset = matches!(*mode, Mode::Stopwatch { .. });
+        if reset {
+            *mode = Mode::Clock;
+        }
+        reset
+    })
+}
+
+/// 秒表在走的时候返回到下一个十分之一秒的计数，其它时候按整秒刷新就够了，返回None
+pub fn 下次刷新(cs: CriticalSection, now: u64) -> Option<u64> {
+    match &*MODE.borrow_ref(cs) {
+        Mode::Stopwatch {
+            start,
+            stopped: None,
+            ..
+        } => Some(TENTH - (now - start) % TENTH),
+        _ => None,
+    }
+}
+
+/// 剩下的秒数向上取整，不到一秒也算一秒，只有到了截止时间才是0
+fn 剩余秒数(deadline: u64) -> u32 {
+    deadline
+        .saturating_sub(SystemTimer::now())
+        .div_ceil(SystemTimer::TICKS_PER_SECOND) as u32
+}
+
+/// 时钟模式下按12/24小时制换算小时，12小时制里零点和中午都显示12
+fn 按格式显示(local: DateTime) -> DateTime {
+    if !screen::时钟格式().hour12 {
+        return local;
+    }
+    DateTime {
+        hour: match local.hour % 12 {
+            0 => 12,
+            hour => hour,
+        },
+        ..local
+    }
+}
+
+/// 秒表在用时返回上次显示的十分之一秒
+pub fn 秒表十分位() -> Option<u8> {
+    critical_section::with(|cs| {
+        matches!(*MODE.borrow_ref(cs), Mode::Stopwatch { .. }).then(|| TENTHS.borrow(cs).get())
+    })
+}
+
+/// 倒计时结束回到时钟后，返回一次true，这时要整个重画数字区
+pub fn 需要重画(cs: CriticalSection) -> bool {
+    REDRAW.borrow(cs).replace(false)
+}
+
+/// 数字区是不是在显示时钟
+pub fn 是时钟() -> bool {
+    critical_section::with(|cs| matches!(*MODE.borrow_ref(cs), Mode::Clock))
+}
+
+/// 数字区是不是在显示秒表
+pub fn 是秒表() -> bool {
+    critical_section::with(|cs| matches!(*MODE.borrow_ref(cs), Mode::Stopwatch { .. }))
+}
+
+/// 按现在的模式算出数字区该显示什么，日期行始终跟着当地时间。
+/// 倒计时到零时把要点亮的灯放进READY队列，由主循环去点，然后回到时钟
+pub fn 显示内容(cs: CriticalSection, local: DateTime) -> DateTime {
+    let mut mode = MODE.borrow_ref_mut(cs);
+    let remaining = match &mut *mode {
+        Mode::Clock => return 按格式显示(local),
+        Mode::Stopwatch { start, stopped, .. } => {
+            let tenths = (stopped.unwrap_or_else(SystemTimer::now) - *start) / TENTH;
+            let secs = tenths / 10;
+            TENTHS.borrow(cs).set((tenths % 10) as u8);
+            return DateTime {
+                hour: (secs / 3600 % 100) as u8,
+                min: (secs / 60 % 60) as u8,
+                sec: (secs % 60) as u8,
+                ..local
+            };
+        }
+        Mode::Countdown {
+            paused: Some(remaining),
+            ..
+        } => *remaining,
+        Mode::Countdown { deadline, lamp, .. } => {
+            let now = SystemTimer::now();
+            if now < *deadline {
+                剩余秒数(*deadline)
+            } else {
+                // 到零时马上点灯，00:00再显示一秒才回到时钟
+                if let Some((color, position)) = lamp.take() {
+                    crate::command::READY
+                        .borrow_ref_mut(cs)
+                        .as_mut()
+                        .unwrap()
+                        .push_back(Command::Blink(color, position));
+                }
+                if now >= *deadline + SystemTimer::TICKS_PER_SECOND {
+                    *mode = Mode::Clock;
+                    REDRAW.borrow(cs).set(true);
+                    return 按格式显示(local);
+                }
+                0
+            }
+        }
+    };
+    DateTime {
+        hour: (remaining / 3600) as u8,
+        min: (remaining / 60 % 60) as u8,
+        sec: (remaining % 60) as u8,
+        ..local
+    }
+}
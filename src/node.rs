@@ -0,0 +1,106 @@
+//! 同一个LoRa信道上有多块板子时的寻址。
+//! 命令前面可以加目标：`3:`只给3号节点，`g2:`给2号组里的节点，`*:`给所有节点，不加目标的命令所有节点都执行。
+//! 节点的回复前面都带上自己的地址`3>`，其它节点收到这样的行直接忽略。
+//! 目标和地址后面还可以带跳数`3~2:`，见[`relay`](crate::relay)
+
+use clock_core::address::is_address;
+pub use clock_core::address::{Target, MAX_GROUP};
+use core::cell::Cell;
+use critical_section::Mutex;
+
+/// 本节点的地址和所在的组
+pub static NODE: Mutex<Cell<Node>> = Mutex::new(Cell::new(Node {
+    address: 0,
+    groups: 0,
+    relay: false,
+}));
+
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    pub address: u16,
+    /// 第n位是1表示在n号组里
+    pub groups: u32,
+    /// 是否转发带跳数的消息
+    pub relay: bool,
+}
+
+/// 本节点的地址
+pub fn 地址() -> u16 {
+    critical_section::with(|cs| NODE.borrow(cs).get().address)
+}
+
+pub fn 设置地址(address: u16) {
+    critical_section::with(|cs| {
+        let node = NODE.borrow(cs);
+        node.set(Node {
+            address,
+            ..node.get()
+        });
+    });
+}
+
+/// 加入或者退出一个组
+pub fn 设置组(group: u8, join: bool) {
+    critical_section::with(|cs| {
+        let node = NODE.borrow(cs);
+        let mut value = node.get();
+        if join {
+            value.groups |= 1 << group;
+        } else {
+            value.groups &= !(1 << group);
+        }
+        node.set(value);
+    });
+}
+
+/// 打开或者关闭中继
+pub fn 设置中继(relay: bool) {
+    critical_section::with(|cs| {
+        let node = NODE.borrow(cs);
+        node.set(Node {
+            relay,
+            ..node.get()
+        });
+    });
+}
+
+/// 本节点是否在当中继
+pub fn 是中继() -> bool {
+    critical_section::with(|cs| NODE.borrow(cs).get().relay)
+}
+
+/// 把行首的目标前缀拆出来，没有前缀时返回None和原来的行。
+/// 只有冒号前面是数字、`*`或者`g数字`时才算前缀，`time 12:30`这样的命令不受影响
+pub fn 拆分目标(line: &str) -> (Option<Target>, &str) {
+    let Some((prefix, rest)) = line.split_once(':') else {
+        return (None, line);
+    };
+    // 跳数只有中继关心
+    let prefix = prefix.split_once('~').map_or(prefix, |(prefix, _)| prefix);
+    match Target::parse(prefix) {
+        Some(target) => (Some(target), rest.trim_start()),
+        None => (None, line),
+    }
+}
+
+/// 其它节点的回复，行首是`地址>`或者`地址~跳数>`，拆成回复方的地址和内容，不是回复时返回None
+pub fn 拆分回复(line: &str) -> Option<(u16, &str)> {
+    let (address, body) = line.split_once('>')?;
+    let address = address
+        .split_once('~')
+        .map_or(address, |(address, _)| address);
+    if !is_address(address) {
+        return None;
+    }
+    Some((address.parse().ok()?, body))
+}
+
+/// 这个目标包不包括本节点，没有目标的命令算作发给所有节点
+pub fn 是发给我的(target: Option<Target>) -> bool {
+    let node = critical_section::with(|cs| NODE.borrow(cs).get());
+    match target {
+        None | Some(Target::All) => true,
+        Some(Target::Node(address)) => address == node.address,
+        Some(Target::Group(group)) => node.groups & 1 << group != 0,
+    }
+}
@@ -1,7 +1,11 @@
-use crate::time::UpdateIndex;
-use crate::{command::Command, time::NOW};
-use alloc::borrow::ToOwned;
-use core::mem::MaybeUninit;
+use crate::calendar::{diff, DateTime, UpdateIndex, WEEKDAY};
+use crate::command::{ClockOption, CommandErr, Position};
+use crate::lamp::{Lamp, LampBank, LampState, Layout, LAMP_BANK, LAMP_STATE};
+use crate::mode;
+use crate::time::{显示时间, 本地时间};
+use alloc::{format, vec::Vec};
+use core::{cell::Cell, fmt::Display, mem::MaybeUninit};
+use critical_section::Mutex;
 use embedded_graphics::{
     mono_font::{
         ascii::{FONT_6X10, FONT_7X14},
@@ -10,7 +14,7 @@ use embedded_graphics::{
     pixelcolor::Rgb565,
     prelude::*,
     primitives::Circle,
-    primitives::{PrimitiveStyle, PrimitiveStyleBuilder},
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
     text::Text,
 };
 use embedded_hal::digital::OutputPin;
@@ -56,21 +60,88 @@ static mut TIME_TEXT: (
     ),
 ) = (
     (
-        Text::new(NUM[0], Point::new(40, 60), STYLE),
-        Text::new(NUM[0], Point::new(50, 60), STYLE),
+        Text::new(NUM[0], Point::new(38, 60), STYLE),
+        Text::new(NUM[0], Point::new(47, 60), STYLE),
     ),
     (
-        Text::new(NUM[0], Point::new(60, 60), STYLE),
-        Text::new(NUM[0], Point::new(70, 60), STYLE),
+        Text::new(NUM[0], Point::new(62, 60), STYLE),
+        Text::new(NUM[0], Point::new(71, 60), STYLE),
     ),
     (
-        Text::new(NUM[0], Point::new(80, 60), STYLE),
-        Text::new(NUM[0], Point::new(90, 60), STYLE),
+        Text::new(NUM[0], Point::new(86, 60), STYLE),
+        Text::new(NUM[0], Point::new(95, 60), STYLE),
     ),
 );
 
-const RIGHT_X: u16 = 159;
-const BOTTOM_Y: u16 = 79;
+/// 时和分、分和秒之间的分隔符
+static mut SEPARATOR: (
+    Text<'_, MonoTextStyle<'_, Rgb565>>,
+    Text<'_, MonoTextStyle<'_, Rgb565>>,
+) = (
+    Text::new(":", Point::new(55, 60), STYLE),
+    Text::new(":", Point::new(79, 60), STYLE),
+);
+
+/// 12小时制时在秒后面显示AM/PM，秒表在这里显示十分之一秒
+static mut MERIDIEM: Text<'_, MonoTextStyle<'_, Rgb565>> = Text::new(
+    "AM",
+    Point::new(105, 60),
+    MonoTextStyle::new(&FONT_6X10, TEXT_COLOR),
+);
+
+/// 时钟的显示方式，(命令格式：clock 12|24 / clock blink on|off / clock seconds on|off)
+#[derive(Debug, Clone, Copy)]
+pub struct ClockFormat {
+    /// 12小时制，带AM/PM
+    pub hour12: bool,
+    /// 冒号每秒闪一下
+    pub blink: bool,
+    /// 显示秒
+    pub seconds: bool,
+}
+
+impl Display for ClockFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let on_off = |value: bool| if value { "on" } else { "off" };
+        write!(
+            f,
+            "{}h blink {} seconds {}",
+            if self.hour12 { 12 } else { 24 },
+            on_off(self.blink),
+            on_off(self.seconds)
+        )
+    }
+}
+
+/// 当前的显示方式，上电时是24小时制、冒号不闪、显示秒
+pub static CLOCK_FORMAT: Mutex<Cell<ClockFormat>> = Mutex::new(Cell::new(ClockFormat {
+    hour12: false,
+    blink: false,
+    seconds: true,
+}));
+
+/// 当前的显示方式
+pub fn 时钟格式() -> ClockFormat {
+    critical_section::with(|cs| CLOCK_FORMAT.borrow(cs).get())
+}
+
+/// 修改一项显示方式，返回修改后的设置，调用的人负责重画
+pub fn 设置时钟格式(option: ClockOption) -> ClockFormat {
+    critical_section::with(|cs| {
+        let format = CLOCK_FORMAT.borrow(cs);
+        let mut value = format.get();
+        match option {
+            ClockOption::Hour12(hour12) => value.hour12 = hour12,
+            ClockOption::Blink(blink) => value.blink = blink,
+            ClockOption::Seconds(seconds) => value.seconds = seconds,
+        }
+        format.set(value);
+        value
+    })
+}
+
+pub const RIGHT_X: u16 = 159;
+pub const BOTTOM_Y: u16 = 79;
 
 pub fn 屏幕初始化<SPI, DC, RST>(device: &mut ST7735<SPI, DC, RST>, delay: &mut Delay)
 where
@@ -113,32 +184,126 @@ where
     }
 }
 
-pub fn 改变灯的颜色(command: &Command) {
+fn 绘制灯<D>(device: &mut D, lamp: &Lamp, color: Rgb565)
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let circle = Circle::with_center(lamp.center, lamp.radius * 2);
+    let style = PrimitiveStyleBuilder::new().fill_color(color).build();
+    println!("{:?}", circle);
+    circle.into_styled(style).draw(device).unwrap();
+}
+
+pub fn 改变灯的颜色(color: Rgb565, position: &Position) -> Result<(), CommandErr> {
+    let lamp = critical_section::with(|cs| {
+        let bank = LAMP_BANK.borrow_ref(cs);
+        let bank = bank.as_ref().unwrap();
+        let index = bank.index_of(position)?;
+        LAMP_STATE
+            .borrow_ref_mut(cs)
+            .as_mut()
+            .unwrap()
+            .set(index, color);
+        Some(bank.lamps()[index].clone())
+    })
+    .ok_or(CommandErr::NoSuchLamp)?;
+    unsafe {
+        绘制灯(&mut *ST7735.as_mut_ptr(), &lamp, color);
+    }
+    Ok(())
+}
+
+/// 换一套灯的排布，旧的灯会被擦掉，新的灯都是熄灭状态
+pub fn 重新排布灯(layout: &Layout) {
+    let old = critical_section::with(|cs| {
+        let bank = LampBank::new(layout);
+        LAMP_STATE
+            .borrow_ref_mut(cs)
+            .replace(LampState::new(bank.len()));
+        LAMP_BANK.borrow_ref_mut(cs).replace(bank)
+    });
     unsafe {
         let device = &mut *ST7735.as_mut_ptr();
-        if let Command::Blink(color, position) = command {
-            let circle = Circle::with_center(
-                Point::new(
-                    match position {
-                        crate::command::Position::Left => (RIGHT_X / 4) as i32,
-                        crate::command::Position::Middle => (RIGHT_X / 2) as i32,
-                        crate::command::Position::Right => (RIGHT_X / 4 * 3) as i32,
-                    },
-                    (BOTTOM_Y / 2) as i32,
-                ),
-                20,
-            );
-            let style = PrimitiveStyleBuilder::new()
-                .fill_color(color.to_owned())
-                .build();
-            println!("{:?}", circle);
-            circle.into_styled(style).draw(device).unwrap();
+        if let Some(old) = old {
+            for lamp in old.lamps() {
+                绘制灯(device, lamp, BG_COLOR);
+            }
+        }
+        // 擦灯时可能把数字和日期也擦掉了
+        绘制数字(device);
+        绘制日期(device);
+    }
+}
+
+/// 所有点亮过的灯和它们的颜色
+fn 点亮的灯() -> Vec<(Lamp, Rgb565)> {
+    critical_section::with(|cs| {
+        let bank = LAMP_BANK.borrow_ref(cs);
+        let state = LAMP_STATE.borrow_ref(cs);
+        let (bank, state) = (bank.as_ref().unwrap(), state.as_ref().unwrap());
+        bank.lamps()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, lamp)| Some((lamp.clone(), state.get(index)?)))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// 把边框、时间和所有灯的颜色都画回去
+unsafe fn 绘制全部<SPI, DC, RST>(device: &mut ST7735<SPI, DC, RST>)
+where
+    SPI: embedded_hal::spi::SpiDevice,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    绘制边框(device);
+    绘制数字(device);
+    绘制日期(device);
+    for (lamp, color) in 点亮的灯().iter() {
+        绘制灯(device, lamp, *color);
+    }
+}
+
+/// 重新初始化屏幕，并把边框、时间和所有灯的颜色都画回去
+pub fn 重绘屏幕(delay: &mut Delay) {
+    unsafe {
+        let device = &mut *ST7735.as_mut_ptr();
+        屏幕初始化(device, delay);
+        绘制全部(device);
+    }
+}
+
+/// 闹钟响时整屏闪烁，`on`时整屏涂成闹钟的颜色，否则画回原来的内容
+pub fn 整屏闪烁(color: Rgb565, on: bool) {
+    unsafe {
+        let device = &mut *ST7735.as_mut_ptr();
+        if on {
+            device.clear(color).unwrap();
         } else {
-            panic!("Unknown command")
+            device.clear(BG_COLOR).unwrap();
+            绘制全部(device);
         }
     }
 }
 
+/// 闹钟响时闪烁一个灯，灭的时候恢复灯原来的颜色，不改变记录的灯的状态
+pub fn 灯闪烁(color: Rgb565, position: &Position, on: bool) -> Result<(), CommandErr> {
+    let (lamp, old) = critical_section::with(|cs| {
+        let bank = LAMP_BANK.borrow_ref(cs);
+        let bank = bank.as_ref().unwrap();
+        let index = bank.index_of(position)?;
+        let old = LAMP_STATE.borrow_ref(cs).as_ref().unwrap().get(index);
+        Some((bank.lamps()[index].clone(), old))
+    })
+    .ok_or(CommandErr::NoSuchLamp)?;
+    let color = if on { color } else { old.unwrap_or(BG_COLOR) };
+    unsafe {
+        绘制灯(&mut *ST7735.as_mut_ptr(), &lamp, color);
+    }
+    Ok(())
+}
+
 pub fn 出问题了(text: &str) {
     unsafe {
         let device = &mut *ST7735.as_mut_ptr();
@@ -149,103 +314,170 @@ pub fn 出问题了(text: &str) {
     }
 }
 
+/// 在屏幕顶端显示一行消息，会先擦掉上一条
+pub fn 显示消息(text: &str) {
+    unsafe {
+        let device = &mut *ST7735.as_mut_ptr();
+        Rectangle::new(Point::new(1, 1), Size::new(RIGHT_X as u32 - 1, 13))
+            .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
+            .draw(device)
+            .unwrap();
+        let style = MonoTextStyle::new(&FONT_6X10, TEXT_COLOR);
+        Text::new(text, Point::new(4, 11), style)
+            .draw(device)
+            .unwrap();
+    }
+}
+
+/// 擦掉原来的文字再画新的，直接画会和原来的文字叠在一起，画空格就是擦掉
+fn 重画文字<D>(
+    device: &mut D,
+    text: &mut Text<'static, MonoTextStyle<'static, Rgb565>>,
+    value: &'static str,
+) where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    text.bounding_box()
+        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
+        .draw(device)
+        .unwrap();
+    text.text = value;
+    text.draw(device).unwrap();
+}
+
+fn 重画数字<D>(
+    device: &mut D,
+    text: &mut Text<'static, MonoTextStyle<'static, Rgb565>>,
+    digit: u8,
+) where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    重画文字(device, text, NUM[digit as usize]);
+}
+
+/// 时钟模式下按设置决定是否显示秒，倒计时和秒表总是要显示
+fn 显示秒() -> bool {
+    !mode::是时钟() || 时钟格式().seconds
+}
+
+/// 秒表十分之一秒的写法，画文字要的是'static的字符串
+const TENTHS: [&str; 10] = [".0", ".1", ".2", ".3", ".4", ".5", ".6", ".7", ".8", ".9"];
+
+/// 按模式和设置画两个分隔符：冒号闪烁时奇数秒不画，不显示秒时不画第二个
+unsafe fn 绘制分隔符<D>(device: &mut D, local: &DateTime)
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let clock = mode::是时钟();
+    let first = if clock && 时钟格式().blink && local.sec % 2 == 1 {
+        " "
+    } else {
+        ":"
+    };
+    let second = if 显示秒() { first } else { " " };
+    重画文字(device, &mut SEPARATOR.0, first);
+    重画文字(device, &mut SEPARATOR.1, second);
+}
+
+/// 12小时制的时钟在后面画AM/PM，秒表画十分之一秒，其它时候擦掉
+unsafe fn 绘制上下午<D>(device: &mut D)
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let marker = if let Some(tenths) = mode::秒表十分位() {
+        TENTHS[tenths as usize]
+    } else if !mode::是时钟() || !时钟格式().hour12 {
+        "  "
+    } else if 本地时间().hour >= 12 {
+        "PM"
+    } else {
+        "AM"
+    };
+    重画文字(device, &mut MERIDIEM, marker);
+}
+
+/// 把数字、分隔符和AM/PM全部按现在的时间和显示方式重画一遍
 pub unsafe fn 绘制数字<SPI, DC, RST>(device: &mut ST7735<SPI, DC, RST>)
 where
     SPI: embedded_hal::spi::SpiDevice,
     DC: OutputPin,
     RST: OutputPin,
 {
-    TIME_TEXT.0 .0.text = NUM[NOW.hour.0 as usize];
-    TIME_TEXT.0 .0.draw(device).unwrap();
-
-    TIME_TEXT.0 .1.text = NUM[NOW.hour.1 as usize];
-    TIME_TEXT.0 .1.draw(device).unwrap();
-
-    TIME_TEXT.1 .0.text = NUM[NOW.min.0 as usize];
-    TIME_TEXT.1 .0.draw(device).unwrap();
-
-    TIME_TEXT.1 .1.text = NUM[NOW.min.1 as usize];
-    TIME_TEXT.1 .1.draw(device).unwrap();
-
-    TIME_TEXT.2 .0.text = NUM[NOW.sec.0 as usize];
-    TIME_TEXT.2 .0.draw(device).unwrap();
+    let local = 显示时间();
+    重画数字(device, &mut TIME_TEXT.0 .0, local.hour / 10);
+    重画数字(device, &mut TIME_TEXT.0 .1, local.hour % 10);
+    重画数字(device, &mut TIME_TEXT.1 .0, local.min / 10);
+    重画数字(device, &mut TIME_TEXT.1 .1, local.min % 10);
+    if 显示秒() {
+        重画数字(device, &mut TIME_TEXT.2 .0, local.sec / 10);
+        重画数字(device, &mut TIME_TEXT.2 .1, local.sec % 10);
+    } else {
+        重画文字(device, &mut TIME_TEXT.2 .0, " ");
+        重画文字(device, &mut TIME_TEXT.2 .1, " ");
+    }
+    绘制分隔符(device, &local);
+    绘制上下午(device);
+}
 
-    TIME_TEXT.2 .1.text = NUM[NOW.sec.1 as usize];
-    TIME_TEXT.2 .1.draw(device).unwrap();
+/// 在时间下面画一行日期和星期，会先擦掉原来的
+pub unsafe fn 绘制日期<SPI, DC, RST>(device: &mut ST7735<SPI, DC, RST>)
+where
+    SPI: embedded_hal::spi::SpiDevice,
+    DC: OutputPin,
+    RST: OutputPin,
+{
+    let local = 显示时间();
+    Rectangle::new(Point::new(1, 65), Size::new(RIGHT_X as u32 - 1, 11))
+        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
+        .draw(device)
+        .unwrap();
+    let text = format!(
+        "{:04}-{:02}-{:02} {}",
+        local.year, local.month, local.day, WEEKDAY[local.weekday as usize]
+    );
+    let style = MonoTextStyle::new(&FONT_6X10, TEXT_COLOR);
+    Text::new(&text, Point::new(38, 74), style)
+        .draw(device)
+        .unwrap();
 }
 
-pub fn 更新时间() {
+/// 时钟走了之后，只重画新旧两个当地时间之间变了的数字
+pub fn 更新时间(old: &DateTime, local: &DateTime) {
+    log::info!("时钟中断：{}", local);
+    let seconds = 显示秒();
     unsafe {
-        log::info!("时钟中断：{}", NOW);
-        for index in NOW.add_sec().iter() {
-            match &index {
-                UpdateIndex::Hour10 => {
-                    TIME_TEXT
-                        .0
-                         .0
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.0 .0.text = NUM[NOW.hour.0 as usize];
-                    TIME_TEXT.0 .0.draw(&mut *ST7735.as_mut_ptr()).unwrap();
-                }
+        let device = &mut *ST7735.as_mut_ptr();
+        for index in diff(old, local).iter() {
+            match index {
+                UpdateIndex::Hour10 => 重画数字(device, &mut TIME_TEXT.0 .0, local.hour / 10),
                 UpdateIndex::Hour1 => {
-                    TIME_TEXT
-                        .0
-                         .1
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.0 .1.text = NUM[NOW.hour.1 as usize];
-                    TIME_TEXT.0 .1.draw(&mut *ST7735.as_mut_ptr()).unwrap();
-                }
-                UpdateIndex::Min10 => {
-                    TIME_TEXT
-                        .1
-                         .0
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.1 .0.text = NUM[NOW.min.0 as usize];
-                    TIME_TEXT.1 .0.draw(&mut *ST7735.as_mut_ptr()).unwrap();
-                }
-                UpdateIndex::Min1 => {
-                    TIME_TEXT
-                        .1
-                         .1
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.1 .1.text = NUM[NOW.min.1 as usize];
-                    TIME_TEXT.1 .1.draw(&mut *ST7735.as_mut_ptr()).unwrap();
+                    重画数字(device, &mut TIME_TEXT.0 .1, local.hour % 10);
+                    // 上下午只会在小时变的时候切换
+                    绘制上下午(device);
                 }
-                UpdateIndex::Sec10 => {
-                    TIME_TEXT
-                        .2
-                         .0
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.2 .0.text = NUM[NOW.sec.0 as usize];
-                    TIME_TEXT.2 .0.draw(&mut *ST7735.as_mut_ptr()).unwrap();
+                UpdateIndex::Min10 => 重画数字(device, &mut TIME_TEXT.1 .0, local.min / 10),
+                UpdateIndex::Min1 => 重画数字(device, &mut TIME_TEXT.1 .1, local.min % 10),
+                UpdateIndex::Sec10 if seconds => {
+                    重画数字(device, &mut TIME_TEXT.2 .0, local.sec / 10)
                 }
-                UpdateIndex::Sec1 => {
-                    TIME_TEXT
-                        .2
-                         .1
-                        .bounding_box()
-                        .into_styled(PrimitiveStyle::with_fill(BG_COLOR))
-                        .draw(&mut *ST7735.as_mut_ptr())
-                        .unwrap();
-                    TIME_TEXT.2 .1.text = NUM[NOW.sec.1 as usize];
-                    TIME_TEXT.2 .1.draw(&mut *ST7735.as_mut_ptr()).unwrap();
+                UpdateIndex::Sec1 if seconds => {
+                    重画数字(device, &mut TIME_TEXT.2 .1, local.sec % 10)
                 }
+                UpdateIndex::Sec10 | UpdateIndex::Sec1 => {}
+                // 日期一天才变一次，直接整行重画
+                UpdateIndex::Day => 绘制日期(device),
+                UpdateIndex::Month | UpdateIndex::Year => {}
             }
         }
+        if mode::是时钟() && 时钟格式().blink {
+            绘制分隔符(device, local);
+        }
+        if mode::是秒表() {
+            绘制上下午(device);
+        }
     }
 }
@@ -0,0 +1,103 @@
+//! 节点之间通过LoRa串口同步时间。
+//! master定期广播`tsync 毫秒数`，follower收到后发`treq 本地计时`，master原样带回并附上自己的时间`tresp 本地计时 毫秒数`，
+//! follower用往返时间的一半补偿传输延迟，对完时间后回报`toff 偏差毫秒`。
+//! 这几条消息都和回复一样在前面带上发出的节点的地址，master看得出是哪个follower回报的偏差
+
+use core::{cell::Cell, fmt::Display};
+use critical_section::Mutex;
+use esp_hal::systimer::SystemTimer;
+
+/// 默认每分钟广播一次
+pub const DEFAULT_PERIOD: u32 = 60;
+
+/// 广播最短隔几秒，太密了会占满信道，follower也来不及对时
+pub const MIN_PERIOD: u32 = 10;
+
+/// 这个节点在时间同步里的角色
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// 不参与同步
+    Off,
+    /// 每隔若干秒广播一次自己的时间，并回应follower的请求
+    Master(u32),
+    /// 跟着master的时间走
+    Follower,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Role::Off => write!(f, "off"),
+            Role::Master(period) => write!(f, "master {}s", period),
+            Role::Follower => write!(f, "follow"),
+        }
+    }
+}
+
+/// 当前角色
+pub static ROLE: Mutex<Cell<Role>> = Mutex::new(Cell::new(Role::Off));
+
+/// master下一次广播的SystemTimer计数
+static NEXT_BROADCAST: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// follower发出还没收到回复的请求，记的是发出时的本地计时
+static PENDING: Mutex<Cell<Option<i64>>> = Mutex::new(Cell::new(None));
+
+/// 最近一次同步时调整的毫秒数
+pub static LAST_OFFSET: Mutex<Cell<Option<i64>>> = Mutex::new(Cell::new(None));
+
+/// 上电以来的毫秒数，只用来算往返时间，不受设置时间影响
+pub fn 本地计时() -> i64 {
+    (SystemTimer::now() * 1000 / SystemTimer::TICKS_PER_SECOND) as i64
+}
+
+/// 切换角色，成为master时马上广播一次
+pub fn 设置角色(role: Role) {
+    critical_section::with(|cs| {
+        ROLE.borrow(cs).set(role);
+        NEXT_BROADCAST.borrow(cs).set(SystemTimer::now());
+        PENDING.borrow(cs).set(None);
+    });
+}
+
+/// master到了广播时间时返回true，并把下一次广播往后推一个周期
+pub fn 该广播了(now: u64) -> bool {
+    critical_section::with(|cs| {
+        let Role::Master(period) = ROLE.borrow(cs).get() else {
+            return false;
+        };
+        let next = NEXT_BROADCAST.borrow(cs);
+        if now < next.get() {
+            return false;
+        }
+        next.set(now + period as u64 * SystemTimer::TICKS_PER_SECOND);
+        true
+    })
+}
+
+/// follower收到广播后发起一次请求，返回要带在请求里的本地计时，不是follower时返回None
+pub fn 发出请求() -> Option<i64> {
+    critical_section::with(|cs| {
+        if ROLE.borrow(cs).get() != Role::Follower {
+            return None;
+        }
+        let sent = 本地计时();
+        PENDING.borrow(cs).set(Some(sent));
+        Some(sent)
+    })
+}
+
+/// 收到master的回复，是自己发出的请求时返回master此刻的时间（毫秒），
+/// 别的节点的回复或者过期的回复返回None
+pub fn 收到回复(sent: i64, master: i64) -> Option<i64> {
+    critical_section::with(|cs| {
+        let pending = PENDING.borrow(cs);
+        if pending.get() != Some(sent) {
+            return None;
+        }
+        pending.set(None);
+        // 假设来回路上花的时间一样，master回复时的时间再加上半个往返就是现在的时间
+        let round_trip = 本地计时() - sent;
+        Some(master + round_trip / 2)
+    })
+}
@@ -1,7 +1,11 @@
-use crate::{command, screen};
-use alloc::vec::Vec;
-use core::{cell::RefCell, fmt::Display};
-use critical_section::Mutex;
+use crate::calendar::{days_from_civil, parse_iso, Date, DateTime, DstRule, Zone};
+use crate::flash::{self, FlashErr};
+use crate::{alarm, backup, command, mode, screen};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+};
+use critical_section::{CriticalSection, Mutex};
 use esp_hal::{
     peripherals::TIMG0,
     prelude::*,
@@ -15,42 +19,51 @@ use esp_println::println;
 pub static ALARM0: Mutex<RefCell<Option<Alarm<Target, Blocking, 0>>>> =
     Mutex::new(RefCell::new(None));
 
+/// 把闹钟对准最早的截止时间，没有任务时关掉闹钟中断
+pub fn 设置闹钟(alarm0: &mut Alarm<Target, Blocking, 0>, deadline: Option<u64>) {
+    match deadline {
+        Some(deadline) => {
+            alarm0.set_target(deadline);
+            alarm0.enable_interrupt(true);
+        }
+        None => alarm0.enable_interrupt(false),
+    }
+}
+
+/// 墙上时间变了之后，按现在显示的时间重新计算按时刻执行的任务，并重新设置闹钟
+pub fn 重新计算定时任务(cs: CriticalSection) {
+    let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+    let scheduler = scheduler.as_mut().unwrap();
+    scheduler.rebase(SystemTimer::now(), 本地时间().secs_of_day());
+    设置闹钟(
+        ALARM0.borrow_ref_mut(cs).as_mut().unwrap(),
+        scheduler.next_deadline(),
+    );
+}
+
 #[handler(priority = esp_hal::interrupt::Priority::Priority1)]
 pub fn systimer_target0() {
     println!("触发时间中断");
     critical_section::with(|cs| {
         let mut alarm0 = ALARM0.borrow_ref_mut(cs);
         let alarm0 = alarm0.as_mut().unwrap();
-
-        println!(
-            "COMMAND_BUCKET: {:?}",
-            command::COMMAND_BUCKET.borrow_ref(cs).as_ref().unwrap()
-        );
-
-        println!(
-            "DELAY_BUCKET: {:?}",
-            command::DELAY_BUCKET.borrow_ref(cs).as_ref().unwrap()
-        );
-
-        screen::改变灯的颜色(
-            &command::COMMAND_BUCKET
-                .borrow_ref_mut(cs)
-                .as_mut()
-                .unwrap()
-                .pop_front()
-                .unwrap(),
-        );
-
-        let mut delay_bucket = command::DELAY_BUCKET.borrow_ref_mut(cs);
-        let delay_bucket = delay_bucket.as_mut().unwrap();
-        delay_bucket.pop_front().unwrap();
-
         alarm0.clear_interrupt();
 
-        if let Some(delay) = delay_bucket.front() {
-            alarm0.set_target(
-                SystemTimer::now() + (SystemTimer::TICKS_PER_SECOND * (delay.clone() as u64)),
-            );
+        let mut scheduler = command::SCHEDULER.borrow_ref_mut(cs);
+        let scheduler = scheduler.as_mut().unwrap();
+        loop {
+            // 截止时间相同（或者很接近）的任务在同一次中断里一起取出，交给主循环执行
+            let mut ready = command::READY.borrow_ref_mut(cs);
+            let ready = ready.as_mut().unwrap();
+            while let Some(command) = scheduler.pop_due(SystemTimer::now()) {
+                ready.push_back(command);
+            }
+            设置闹钟(alarm0, scheduler.next_deadline());
+            // 设置闹钟时下一个任务可能已经过期了，这时直接接着取，免得错过
+            match scheduler.next_deadline() {
+                Some(deadline) if deadline <= SystemTimer::now() => continue,
+                _ => break,
+            }
         }
     });
 }
@@ -61,124 +74,408 @@ pub static TIMER0: Mutex<RefCell<Option<Timer<Timer0<TIMG0>, esp_hal::Blocking>>
 
 #[handler]
 pub fn tg0_t0_level() {
-    screen::更新时间();
+    // 定时器只负责提醒刷新屏幕，时间本身从SystemTimer算，中断来晚了或者漏了都不会丢秒
+    let (last, utc, old, new, redraw) = critical_section::with(|cs| {
+        let utc = NOW.borrow(cs).get().utc_at(SystemTimer::now());
+        let new = mode::显示内容(cs, 换算本地时间(utc));
+        let old = DIGITS.borrow_ref_mut(cs).replace(new.clone());
+        let redraw = mode::需要重画(cs);
+        (LAST.borrow(cs).replace(utc), utc, old, new, redraw)
+    });
+    match old {
+        // 倒计时换回时钟时显示方式全变了，只比较数字不够
+        _ if redraw => unsafe { screen::绘制数字(&mut *screen::ST7735.as_mut_ptr()) },
+        // 秒表的十分之一秒不在数字里，每次都要画
+        Some(old) if old != new || mode::是秒表() => screen::更新时间(&old, &new),
+        _ => {}
+    }
 
     //清除中断位
     critical_section::with(|cs| {
-        let mut timer0 = TIMER0.borrow_ref_mut(cs);
-        let timer0 = timer0.as_mut().unwrap();
-        timer0.clear_interrupt();
-        timer0.start(1000u64.millis());
+        TIMER0
+            .borrow_ref_mut(cs)
+            .as_mut()
+            .unwrap()
+            .clear_interrupt();
+        等到下次刷新(cs);
+
+        if utc != last {
+            alarm::检查闹钟(cs, &换算本地时间(utc));
+        }
+        backup::保存时钟(cs);
+
+        // 跨过整分钟时按显示的时间重新对一次按时刻执行的任务，夏令时切换也在整分钟，顺便就处理了
+        if utc.div_euclid(60) != last.div_euclid(60) {
+            重新计算定时任务(cs);
+        }
     });
 }
 
-pub static mut NOW: DateTime = DateTime {
-    hour: (0, 0),
-    min: (0, 0),
-    sec: (0, 0),
-};
+/// 让时钟定时器在下一个整秒（秒表在走时是下一个十分之一秒）之后稍晚一点触发
+fn 等到下次刷新(cs: CriticalSection) {
+    let now = SystemTimer::now();
+    let ticks =
+        mode::下次刷新(cs, now).unwrap_or_else(|| NOW.borrow(cs).get().until_next_second(now));
+    // 多等半毫秒，保证醒来时数字已经变了，不会白跑一趟
+    let micros = ticks * 1_000_000 / SystemTimer::TICKS_PER_SECOND + 500;
+    TIMER0
+        .borrow_ref_mut(cs)
+        .as_mut()
+        .unwrap()
+        .start(micros.micros());
+}
+
+/// 时钟的基准：SystemTimer计数为`ticks`的那一刻，UTC是`epoch`秒整，
+/// `ppb`是晶振每十亿个计数里多走（正）或少走（负）的计数，换算时扣掉
+#[derive(Debug, Clone, Copy)]
+pub struct Reference {
+    pub epoch: i64,
+    pub ticks: u64,
+    pub ppb: i32,
+}
+
+impl Reference {
+    /// SystemTimer计数为`ticks`时UTC是`utc_ms`毫秒，基准要落在整秒上，把不足一秒的部分折算成计数往前挪
+    pub fn from_ms(utc_ms: i64, ticks: u64, ppb: i32) -> Reference {
+        let fraction = utc_ms.rem_euclid(1000) as u64 * SystemTimer::TICKS_PER_SECOND / 1000;
+        Reference {
+            epoch: utc_ms.div_euclid(1000),
+            ticks: ticks.wrapping_sub(fraction),
+            ppb,
+        }
+    }
+
+    /// 从基准到`ticks`按晶振误差修正之后的计数
+    fn corrected(&self, ticks: u64) -> u64 {
+        let elapsed = ticks.wrapping_sub(self.ticks) as i128;
+        (elapsed * 1_000_000_000 / (1_000_000_000 + self.ppb as i128)) as u64
+    }
+
+    /// SystemTimer计数为`ticks`时的UTC秒数
+    pub fn utc_at(&self, ticks: u64) -> i64 {
+        self.epoch + (self.corrected(ticks) / SystemTimer::TICKS_PER_SECOND) as i64
+    }
+
+    /// SystemTimer计数为`ticks`时的UTC毫秒数
+    pub fn utc_ms_at(&self, ticks: u64) -> i64 {
+        self.epoch * 1000 + (self.corrected(ticks) * 1000 / SystemTimer::TICKS_PER_SECOND) as i64
+    }
+
+    /// 从`ticks`到下一个整秒还要多少个计数
+    pub fn until_next_second(&self, ticks: u64) -> u64 {
+        let remaining =
+            SystemTimer::TICKS_PER_SECOND - self.corrected(ticks) % SystemTimer::TICKS_PER_SECOND;
+        (remaining as i128 * (1_000_000_000 + self.ppb as i128) / 1_000_000_000) as u64
+    }
+
+    /// 换一个修正值，基准挪到`ticks`所在的那个整秒开头，已经走过的时间不受影响
+    pub fn with_ppb(&self, ticks: u64, ppb: i32) -> Reference {
+        let second = (SystemTimer::TICKS_PER_SECOND as i128 * (1_000_000_000 + self.ppb as i128)
+            / 1_000_000_000) as u64;
+        let next = ticks.wrapping_add(self.until_next_second(ticks));
+        Reference {
+            epoch: self.utc_at(ticks),
+            ticks: next.wrapping_sub(second),
+            ppb,
+        }
+    }
+}
+
+/// 校准晶振的进度，两次设置时间之间SystemTimer走了多少和实际过了多少一比就是晶振的误差
+#[derive(Debug, Clone, Copy)]
+pub enum Calibration {
+    /// 没有在校准
+    Idle,
+    /// 等第一次设置时间
+    Waiting,
+    /// 记下了第一次设置时间时的计数和UTC毫秒数，等第二次
+    First { ticks: u64, utc_ms: i64 },
+}
+
+/// 两次设置时间至少隔这么久，手工设置时间有几百毫秒的误差，隔得太近算出来的误差没有意义
+pub const MIN_CALIBRATION_SECS: i64 = 300;
+
+/// 修正值的上限，普通晶振的误差在几十ppm以内，超过500ppm多半是设置时间时输错了
+pub const MAX_PPB: i32 = 500_000;
+
+/// 校准没有成功的原因
+#[derive(Debug, Clone, Copy)]
+pub enum CalibrationErr {
+    /// 两次设置时间只隔了这么多秒，还在等下一次
+    TooShort(i64),
+    /// 算出来的误差（ppb）超出了范围，这次校准作废
+    OutOfRange(i64),
+}
+
+impl Display for CalibrationErr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CalibrationErr::TooShort(secs) => write!(
+                f,
+                "only {}s since 1st time, set time again after {}s",
+                secs, MIN_CALIBRATION_SECS
+            ),
+            CalibrationErr::OutOfRange(ppb) => {
+                write!(f, "drift {}ppm out of range, cancelled", ppb / 1000)
+            }
+        }
+    }
+}
+
+/// 当前的校准进度
+pub static CALIBRATION: Mutex<Cell<Calibration>> = Mutex::new(Cell::new(Calibration::Idle));
+
+/// 按ppm显示晶振误差，保留三位小数
+pub struct Drift(pub i32);
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let ppb = self.0.unsigned_abs();
+        write!(f, "{}{}.{:03}ppm", sign, ppb / 1000, ppb % 1000)
+    }
+}
+
+/// 时钟的核心：UTC秒数和SystemTimer计数的对应关系，当前时间都从它推算
+pub static NOW: Mutex<Cell<Reference>> = Mutex::new(Cell::new(Reference {
+    epoch: 0,
+    ticks: 0,
+    ppb: 0,
+}));
+
+/// 上一次时钟中断时的UTC秒数，用来判断是否跨过了整分钟
+static LAST: Mutex<Cell<i64>> = Mutex::new(Cell::new(0));
+
+/// 屏幕上正在显示的内容，用来算出哪些数字需要重画
+static DIGITS: Mutex<RefCell<Option<DateTime>>> = Mutex::new(RefCell::new(None));
+
+/// 当前使用的时区，上电时使用编译机器的UTC偏移
+pub static ZONE: Mutex<Cell<Zone>> = Mutex::new(Cell::new(Zone::UTC));
 
-pub struct DateTime {
-    pub hour: (i8, i8),
-    pub min: (i8, i8),
-    pub sec: (i8, i8),
-}
-
-pub enum UpdateIndex {
-    Sec1,
-    Sec10,
-    Min1,
-    Min10,
-    Hour1,
-    Hour10,
-}
-
-impl DateTime {
-    pub fn add_sec(&mut self) -> Vec<UpdateIndex> {
-        //8位，使用6位，分别表示数字是否发生变化
-        let mut ans = Vec::new(); //秒的个位需要变化
-        ans.push(UpdateIndex::Sec1);
-        let sec = self.sec.0 * 10 + self.sec.1 + 1;
-        if sec >= 60 {
-            //更新分
-            self.sec = (0, 0);
-            //分的个位，秒的10位发生变化
-            ans.push(UpdateIndex::Sec10);
-            ans.push(UpdateIndex::Min1);
-            let min = self.min.0 * 10 + self.min.1 + 1;
-            if min >= 60 {
-                //更新时
-                self.min = (0, 0);
-                //分的10位，时的个位发生变化
-                ans.push(UpdateIndex::Min10);
-                ans.push(UpdateIndex::Hour1);
-                let hour = self.hour.0 * 10 + self.hour.1 + 1;
-                if hour >= 24 {
-                    self.hour = (0, 0);
-                    ans.push(UpdateIndex::Hour10);
+/// 现在的UTC秒数
+pub fn 当前秒数() -> i64 {
+    critical_section::with(|cs| NOW.borrow(cs).get().utc_at(SystemTimer::now()))
+}
+
+/// 现在的UTC毫秒数，节点之间同步时间用
+pub fn 当前毫秒() -> i64 {
+    critical_section::with(|cs| NOW.borrow(cs).get().utc_ms_at(SystemTimer::now()))
+}
+
+fn 换算本地时间(utc: i64) -> DateTime {
+    let zone = critical_section::with(|cs| ZONE.borrow(cs).get());
+    DateTime::from_epoch(utc + zone.offset_at(utc) as i64)
+}
+
+/// 按ZONE换算出的当地时间，显示和按时刻执行的任务都用它
+pub fn 本地时间() -> DateTime {
+    换算本地时间(当前秒数())
+}
+
+/// 整个重画屏幕上的时间时用，按当前模式算出要画的内容并记下来，之后的时钟中断从这里接着更新
+pub fn 显示时间() -> DateTime {
+    let local = 本地时间();
+    critical_section::with(|cs| {
+        let shown = mode::显示内容(cs, local);
+        DIGITS.borrow_ref_mut(cs).replace(shown.clone());
+        shown
+    })
+}
+
+/// 切换模式之后马上重画数字，并按新的模式重新安排下一次刷新，不用等下一次时钟中断
+pub fn 重画时间() {
+    critical_section::with(|cs| {
+        unsafe {
+            screen::绘制数字(&mut *screen::ST7735.as_mut_ptr());
+        }
+        等到下次刷新(cs);
+    });
+}
+
+/// 用编译时写入的ISO 8601字符串初始化时钟，编译机器的UTC偏移作为默认时区
+pub fn 初始化时间(value: &[u8]) {
+    match core::str::from_utf8(value).ok().and_then(parse_iso) {
+        Some((date, ms_of_day, offset)) => {
+            let offset = offset.unwrap_or(0);
+            // 这里加13秒是为了中和编译烧录时间
+            let utc_ms =
+                (days_from_civil(date) * 86400 - offset as i64 + 13) * 1000 + ms_of_day as i64;
+            critical_section::with(|cs| {
+                NOW.borrow(cs)
+                    .set(Reference::from_ms(utc_ms, SystemTimer::now(), 0));
+                ZONE.borrow(cs).set(Zone {
+                    offset,
+                    dst: DstRule::None,
+                });
+            });
+        }
+        None => log::warn!("无法解析编译时间 {:?}", value),
+    }
+}
+
+/// 用RTC内存里的备份恢复时钟，修正值和时区也一起恢复，超出范围的修正值不要
+pub fn 恢复时间(utc_ms: i64, ppb: i32, zone: Zone) {
+    let ppb = if ppb.unsigned_abs() > MAX_PPB as u32 {
+        0
+    } else {
+        ppb
+    };
+    critical_section::with(|cs| {
+        NOW.borrow(cs)
+            .set(Reference::from_ms(utc_ms, SystemTimer::now(), ppb));
+        ZONE.borrow(cs).set(zone);
+    });
+}
+
+/// 在运行时修改时间，修改基准、对齐到新的整秒、重画数字都在同一个临界区里完成，
+/// 时钟中断不会看到改了一半的时间。正在校准时顺便记下这次设置，第二次设置时返回新的修正值或者失败的原因
+pub fn 设置时间(
+    date: Option<Date>,
+    ms_of_day: u32,
+    offset: Option<i32>,
+) -> Option<Result<i32, CalibrationErr>> {
+    critical_section::with(|cs| {
+        let zone = ZONE.borrow(cs).get();
+        let date = date.unwrap_or_else(|| 本地时间().date());
+        let epoch = days_from_civil(date) * 86400 + (ms_of_day / 1000) as i64;
+        let offset = match offset {
+            Some(offset) => offset,
+            // 没写偏移就当作当地时间，先按标准时间估一个UTC，再看那时是不是夏令时
+            None => zone.offset_at(epoch - zone.offset as i64),
+        };
+        let utc_ms = (epoch - offset as i64) * 1000 + (ms_of_day % 1000) as i64;
+        let ticks = SystemTimer::now();
+        let mut ppb = NOW.borrow(cs).get().ppb;
+        let calibration = CALIBRATION.borrow(cs);
+        let finished = match calibration.get() {
+            Calibration::Idle => None,
+            Calibration::Waiting => {
+                calibration.set(Calibration::First { ticks, utc_ms });
+                None
+            }
+            Calibration::First {
+                ticks: first,
+                utc_ms: first_ms,
+            } => {
+                let elapsed_ms = utc_ms - first_ms;
+                if elapsed_ms < MIN_CALIBRATION_SECS * 1000 {
+                    Some(Err(CalibrationErr::TooShort(elapsed_ms.div_euclid(1000))))
                 } else {
-                    //不更新时
-                    if hour / 10 != self.hour.0 {
-                        // 10位发生变化
-                        self.hour.0 += 1;
-                        self.hour.1 = 0;
-                    } else {
-                        //10位不发生变化
-                        self.hour.1 += 1;
+                    // 本地计数比实际多出来的比例就是晶振的误差
+                    let expected =
+                        elapsed_ms as i128 * SystemTimer::TICKS_PER_SECOND as i128 / 1000;
+                    let counted = ticks.wrapping_sub(first) as i128;
+                    let drift = (counted - expected) * 1_000_000_000 / expected;
+                    calibration.set(Calibration::Idle);
+                    match i32::try_from(drift) {
+                        Ok(drift) if drift.unsigned_abs() <= MAX_PPB as u32 => {
+                            ppb = drift;
+                            Some(Ok(ppb))
+                        }
+                        _ => Some(Err(CalibrationErr::OutOfRange(
+                            drift.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+                        ))),
                     }
                 }
-            } else {
-                //不更新时
-                if min / 10 != self.min.0 {
-                    // 10位发生变化
-                    ans.push(UpdateIndex::Min10);
-                    self.min.0 += 1;
-                    self.min.1 = 0;
-                } else {
-                    //10位不发生变化
-                    self.min.1 += 1;
-                }
             }
-        } else {
-            //不更新分
-            if sec / 10 != self.sec.0 {
-                // 10位发生变化
-                ans.push(UpdateIndex::Sec10);
-                self.sec.0 += 1;
-                self.sec.1 = 0;
-            } else {
-                //10位不发生变化
-                self.sec.1 += 1;
-            }
-        }
-        ans
-    }
+        };
+        换用基准(cs, Reference::from_ms(utc_ms, ticks, ppb));
+        finished
+    })
 }
 
-impl DateTime {
-    pub fn build(&mut self, value: &[u8]) {
-        let hour = value[0] + ((value[1] + (value[2] + 13 >= 60) as u8) >= 60) as u8 % 24;
-        let hour = hour as i8;
-        self.hour = (hour / 10, hour % 10);
-        // 这里加13秒是为了中和编译烧录时间
-        let min = (value[1] + (value[2] + 13 >= 60) as u8) % 60;
-        let min = min as i8;
-        self.min = (min / 10, min % 10);
-        let sec = (value[2] + 13) % 60;
-        let sec = sec as i8;
-        self.sec = (sec / 10, sec % 10);
+/// 按其他节点传来的UTC毫秒数对时，返回这次调整了多少毫秒
+pub fn 同步时间(utc_ms: i64) -> i64 {
+    critical_section::with(|cs| {
+        let ticks = SystemTimer::now();
+        let now = NOW.borrow(cs).get();
+        let offset = utc_ms - now.utc_ms_at(ticks);
+        // 基准要落在整秒上，把不足一秒的部分折算成计数往前挪
+        换用基准(cs, Reference::from_ms(utc_ms, ticks, now.ppb));
+        offset
+    })
+}
+
+/// 换上新的基准，重画数字、对齐到新的整秒并重新计算按时刻执行的任务
+fn 换用基准(cs: CriticalSection, reference: Reference) {
+    NOW.borrow(cs).set(reference);
+    unsafe {
+        screen::绘制数字(&mut *screen::ST7735.as_mut_ptr());
+        screen::绘制日期(&mut *screen::ST7735.as_mut_ptr());
     }
+    TIMER0
+        .borrow_ref_mut(cs)
+        .as_mut()
+        .unwrap()
+        .clear_interrupt();
+    等到下次刷新(cs);
+
+    重新计算定时任务(cs);
+}
+
+/// 修改时区，NOW本身是UTC不用动，只需要重画并重新计算按时刻执行的任务
+pub fn 设置时区(zone: Zone) {
+    critical_section::with(|cs| {
+        ZONE.borrow(cs).set(zone);
+        unsafe {
+            screen::绘制数字(&mut *screen::ST7735.as_mut_ptr());
+            screen::绘制日期(&mut *screen::ST7735.as_mut_ptr());
+        }
+        重新计算定时任务(cs);
+    });
+}
+
+/// 开始校准晶振，之后的两次设置时间之间隔得越久算出来的误差越准
+pub fn 开始校准() {
+    critical_section::with(|cs| CALIBRATION.borrow(cs).set(Calibration::Waiting));
 }
 
-impl Display for DateTime {
-    /// 该函数不会主动更新时间，不应该在显示时间时使用，这里仅作为调试输出到控制台
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        write!(
-            f,
-            "{:02}:{:02}:{:02}",
-            self.hour.0 * 10 + self.hour.1,
-            self.min.0 * 10 + self.min.1,
-            self.sec.0 * 10 + self.sec.1
-        )
+/// 取消正在进行的校准，并把修正值清零
+pub fn 清除校准() {
+    critical_section::with(|cs| {
+        CALIBRATION.borrow(cs).set(Calibration::Idle);
+        let now = NOW.borrow(cs);
+        now.set(now.get().with_ppb(SystemTimer::now(), 0));
+    });
+}
+
+/// flash里存修正值的标记
+const CALIBRATION_MAGIC: u32 = 0x4341_4c31;
+
+/// 把修正值存进flash，掉电之后不用重新校准
+pub fn 保存修正值(ppb: i32) -> Result<(), FlashErr> {
+    let mut words = [CALIBRATION_MAGIC, ppb as u32, 0];
+    words[2] = 修正值校验(&words);
+    flash::写入扇区(flash::CALIBRATION_ADDR, &words)
+}
+
+fn 修正值校验(words: &[u32; 3]) -> u32 {
+    let mut bytes = [0u8; 8];
+    bytes[0..4].copy_from_slice(&words[0].to_le_bytes());
+    bytes[4..8].copy_from_slice(&words[1].to_le_bytes());
+    backup::crc32(&bytes)
+}
+
+/// 掉过电时从flash读出修正值接着用，没有存过、内容无效或者超出范围时不动
+pub fn 恢复修正值() {
+    let Ok(words) = flash::读取::<3>(flash::CALIBRATION_ADDR) else {
+        return;
+    };
+    let ppb = words[1] as i32;
+    if words[0] != CALIBRATION_MAGIC
+        || words[2] != 修正值校验(&words)
+        || ppb.unsigned_abs() > MAX_PPB as u32
+    {
+        return;
     }
+    log::info!("从flash读出修正值 {}", Drift(ppb));
+    critical_section::with(|cs| {
+        let now = NOW.borrow(cs);
+        now.set(now.get().with_ppb(SystemTimer::now(), ppb));
+    });
+}
+
+/// 当前的修正值和校准进度
+pub fn 校准状态() -> (i32, Calibration) {
+    critical_section::with(|cs| (NOW.borrow(cs).get().ppb, CALIBRATION.borrow(cs).get()))
 }